@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rproxychainsd::codec::{Decode, Decoded};
+use rproxychainsd::http::{HttpConnectReply, HttpConnectRequest};
+use rproxychainsd::socks4::{Socks4Command, Socks4Reply};
+use rproxychainsd::socks5::{
+    Socks5AuthReply, Socks5AuthRequest, Socks5Command, Socks5PasswordReply, Socks5PasswordRequest,
+    Socks5Reply,
+};
+
+// A decoder must never claim more bytes than it was given
+fn check<T: Decode>(data: &[u8]) {
+    if let Ok(Decoded::Complete(_, len)) = T::decode(data) {
+        assert!(len <= data.len());
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check::<Socks4Command>(data);
+    check::<Socks4Reply>(data);
+    check::<Socks5AuthRequest>(data);
+    check::<Socks5AuthReply>(data);
+    check::<Socks5PasswordRequest>(data);
+    check::<Socks5PasswordReply>(data);
+    check::<Socks5Command>(data);
+    check::<Socks5Reply>(data);
+    check::<HttpConnectRequest>(data);
+    check::<HttpConnectReply>(data);
+});
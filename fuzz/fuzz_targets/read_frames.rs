@@ -0,0 +1,36 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rproxychainsd::codec::{read_frame, Decode};
+use rproxychainsd::http::{HttpConnectReply, HttpConnectRequest};
+use rproxychainsd::socks4::{Socks4Command, Socks4Reply};
+use rproxychainsd::socks5::{
+    Socks5AuthReply, Socks5AuthRequest, Socks5Command, Socks5PasswordReply, Socks5PasswordRequest,
+    Socks5Reply,
+};
+use tokio::runtime::Builder;
+
+// Whatever the input, reading a frame has to stop at some point without reading past it, which
+// is what keeps the buffer bounded
+async fn check<T: Decode>(data: &[u8]) {
+    let mut stream = data;
+    let _ = read_frame::<T, _>(&mut stream).await;
+    assert!(data.len() - stream.len() <= 8192);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        check::<Socks4Command>(data).await;
+        check::<Socks4Reply>(data).await;
+        check::<Socks5AuthRequest>(data).await;
+        check::<Socks5AuthReply>(data).await;
+        check::<Socks5PasswordRequest>(data).await;
+        check::<Socks5PasswordReply>(data).await;
+        check::<Socks5Command>(data).await;
+        check::<Socks5Reply>(data).await;
+        check::<HttpConnectRequest>(data).await;
+        check::<HttpConnectReply>(data).await;
+    });
+});
@@ -0,0 +1,156 @@
+use crate::config::{AccessLog as AccessLogConfig, Config};
+use crate::listener::ClientAddr;
+use crate::session::Termination;
+use crate::socks::Protocol;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::to_vec;
+use std::io::{ErrorKind, Result as IoResult};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{remove_file, rename, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch::Receiver;
+use tokio::task::{spawn, JoinHandle};
+use tracing::error;
+
+#[derive(Serialize, Debug)]
+pub struct Record {
+    pub timestamp: u64,
+    pub session: u64,
+    pub listener: String,
+    pub client: ClientAddr,
+    pub identity: Option<String>,
+    pub protocol: Option<Protocol>,
+    pub destination: Option<String>,
+    pub chain: Vec<String>,
+    pub exit_country: Option<String>,
+    pub exit_asn: Option<String>,
+    pub bound: Option<SocketAddr>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub handshake_ms: Option<u64>,
+    pub duration_ms: u64,
+    pub termination: Termination,
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct AccessLog {
+    sender: UnboundedSender<Record>,
+}
+
+struct Output {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl AccessLog {
+    // The writer follows config reloads, so the log can be enabled, disabled or moved with SIGHUP.
+    // It finishes once every handle is dropped and all queued records are written.
+    pub fn spawn(config: Receiver<Arc<Config>>) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = unbounded_channel();
+        let writer = spawn(write_records(receiver, config));
+
+        let access_log = Self {
+            sender,
+        };
+
+        (access_log, writer)
+    }
+
+    pub fn log(&self, record: Record) {
+        let _ = self.sender.send(record);
+    }
+}
+
+impl Output {
+    async fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        let size = file.metadata().await?.len();
+
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+        })
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    path.into()
+}
+
+fn ignore_missing(result: IoResult<()>) -> Result<()> {
+    match result {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error)?,
+        _ => Ok(()),
+    }
+}
+
+// access.log -> access.log.1 -> ... -> access.log.<max_files>, the oldest one is dropped
+async fn rotate(config: &AccessLogConfig) -> Result<()> {
+    let path = config.path();
+
+    if config.max_files() == 0 {
+        return ignore_missing(remove_file(path).await);
+    }
+
+    for index in (1..config.max_files()).rev() {
+        let from = rotated_path(path, index);
+        let to = rotated_path(path, index + 1);
+        ignore_missing(rename(&from, &to).await)?;
+    }
+
+    ignore_missing(rename(path, rotated_path(path, 1)).await)
+}
+
+async fn write_record(
+    output: &mut Option<Output>,
+    config: &AccessLogConfig,
+    record: &Record,
+) -> Result<()> {
+    let mut line = to_vec(record)?;
+    line.push(b'\n');
+
+    let current = match output {
+        Some(current) if current.path == config.path() => current,
+        _ => output.insert(Output::open(config.path()).await?),
+    };
+
+    if current.size > 0 && current.size + line.len() as u64 > config.max_size() {
+        rotate(config).await?;
+        *current = Output::open(config.path()).await?;
+    }
+
+    current.file.write_all(&line).await?;
+    current.file.flush().await?;
+    current.size += line.len() as u64;
+    Ok(())
+}
+
+async fn write_records(mut receiver: UnboundedReceiver<Record>, config: Receiver<Arc<Config>>) {
+    let mut output = None;
+
+    while let Some(record) = receiver.recv().await {
+        let config = config.borrow().clone();
+
+        let access_log = match config.access_log() {
+            Some(access_log) => access_log,
+            None => {
+                output = None;
+                continue;
+            }
+        };
+
+        if let Err(error) = write_record(&mut output, access_log, &record).await {
+            error!(%error, path = %access_log.path().display(), "Could not write access log");
+            output = None;
+        }
+    }
+}
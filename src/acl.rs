@@ -0,0 +1,146 @@
+use anyhow::{Error as AnyError, Result};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("connection to {0} denied by ACL")]
+    Denied(SocketAddr),
+    #[error("expected an address or network like 10.0.0.0/8")]
+    InvalidNetwork,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(try_from = "String")]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+// Every field that is given has to match, a rule without any matches everything
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    action: Action,
+    clients: Option<Vec<String>>,
+    destinations: Option<Vec<Network>>,
+    ports: Option<Vec<u16>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(transparent)]
+pub struct Acl(Vec<Rule>);
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// A bare address is a network with just that address in it
+impl FromStr for Network {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| Error::InvalidNetwork)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| Error::InvalidNetwork)?,
+            None => max,
+        };
+
+        if prefix > max {
+            Err(Error::InvalidNetwork)?;
+        }
+
+        Ok(Self {
+            addr,
+            prefix,
+        })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = AnyError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Rule {
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn clients(&self) -> &[String] {
+        self.clients.as_deref().unwrap_or_default()
+    }
+
+    pub fn matches(&self, client: Option<&str>, destination: SocketAddr) -> bool {
+        let client_matches = match &self.clients {
+            Some(clients) => client.is_some_and(|client| clients.iter().any(|name| name == client)),
+            None => true,
+        };
+
+        let destination_matches = match &self.destinations {
+            Some(networks) => networks.iter().any(|network| network.contains(destination.ip())),
+            None => true,
+        };
+
+        let port_matches = match &self.ports {
+            Some(ports) => ports.contains(&destination.port()),
+            None => true,
+        };
+
+        client_matches && destination_matches && port_matches
+    }
+}
+
+impl Acl {
+    // What the first matching rule says, if any matches
+    pub fn decide(&self, client: Option<&str>, destination: SocketAddr) -> Option<Action> {
+        self.0.iter().find(|rule| rule.matches(client, destination)).map(Rule::action)
+    }
+}
+
+impl Deref for Acl {
+    type Target = [Rule];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config::Proxy;
+use crate::context::Context;
+use crate::proxies::Health;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, json, to_string, Value};
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::fs::{remove_file, set_permissions, symlink_metadata};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::spawn;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+
+// Far more than any request needs
+const MAX_REQUEST: u64 = 64 * 1024;
+
+// Pause after a failed accept, which would most likely fail again right away
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("'{0}' exists and is not a socket")]
+    NotASocket(String),
+    #[error("no session with id {0}")]
+    UnknownSession(u64),
+    #[error("{0} is not in the config")]
+    UnknownProxy(String),
+    #[error("no response from the daemon")]
+    NoResponse,
+    #[error("request longer than {0} bytes")]
+    RequestTooLong(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Mark {
+    Up,
+    Down,
+    Auto,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Sessions,
+    Kill { id: u64 },
+    Mark { proxy: String, state: Mark },
+    Rotate,
+    Reload,
+    Stats,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Mark> for Option<Health> {
+    fn from(value: Mark) -> Self {
+        match value {
+            Mark::Up => Some(Health::Up),
+            Mark::Down => Some(Health::Down),
+            Mark::Auto => None,
+        }
+    }
+}
+
+impl From<Result<Value>> for Response {
+    fn from(value: Result<Value>) -> Self {
+        match value {
+            Ok(result) => Self {
+                ok: true,
+                result: Some(result),
+                error: None,
+            },
+            Err(error) => Self {
+                ok: false,
+                result: None,
+                error: Some(error.to_string()),
+            },
+        }
+    }
+}
+
+async fn execute(context: &Context, request: Request) -> Result<Value> {
+    match request {
+        Request::Sessions => Ok(json!(context.sessions().list())),
+        Request::Kill {
+            id,
+        } => {
+            if !context.sessions().kill(id) {
+                return Err(Error::UnknownSession(id))?;
+            }
+
+            info!(session = id, "Session killed through admin socket");
+            Ok(Value::Null)
+        }
+        Request::Mark {
+            proxy,
+            state,
+        } => {
+            let proxy: Proxy = proxy.parse()?;
+
+            if !context.reloader().subscribe().borrow().contains(&proxy) {
+                return Err(Error::UnknownProxy(proxy.to_string()))?;
+            }
+
+            context.proxies().mark(&proxy, state.into());
+            info!(%proxy, ?state, "Proxy marked through admin socket");
+            Ok(Value::Null)
+        }
+        Request::Rotate => {
+            context.selector().rotate();
+            context.warm_pool().clear();
+            info!("Chain rotated through admin socket");
+            Ok(Value::Null)
+        }
+        Request::Reload => {
+            context.reloader().reload().await?;
+            info!("Config reloaded through admin socket");
+            Ok(Value::Null)
+        }
+        Request::Stats => {
+            let stats = context.proxies().snapshot();
+            Ok(json!(stats.into_iter().collect::<BTreeMap<_, _>>()))
+        }
+    }
+}
+
+async fn handle_client(context: Context, stream: UnixStream) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+
+    loop {
+        let mut line = String::new();
+        let num = (&mut reader).take(MAX_REQUEST).read_line(&mut line).await?;
+
+        if num == 0 {
+            return Ok(());
+        }
+
+        // The rest of an overlong line can't be told apart from the next request, so the client
+        // is hung up on after the error
+        let too_long = num as u64 == MAX_REQUEST && !line.ends_with('\n');
+
+        let request = match too_long {
+            true => Err(Error::RequestTooLong(MAX_REQUEST).into()),
+            false => from_str::<Request>(&line).map_err(Into::into),
+        };
+
+        let response = match request {
+            Ok(request) => {
+                debug!(?request, "Admin request");
+                execute(&context, request).await.into()
+            }
+            Err(error) => Response::from(Err(error)),
+        };
+
+        let mut response = to_string(&response)?;
+        response.push('\n');
+        write.write_all(response.as_bytes()).await?;
+
+        if too_long {
+            return Ok(());
+        }
+    }
+}
+
+// Only a leftover socket from an earlier run is removed, never a regular file
+pub async fn remove_stale_socket(path: &Path) -> Result<()> {
+    match symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => Ok(remove_file(path).await?),
+        Ok(_) => Err(Error::NotASocket(path.display().to_string()))?,
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error)?,
+    }
+}
+
+pub async fn serve(context: Context, path: &Path, mode: u32) -> Result<()> {
+    remove_stale_socket(path).await?;
+    let listener = UnixListener::bind(path)?;
+    set_permissions(path, Permissions::from_mode(mode)).await?;
+    info!(path = %path.display(), "Admin socket running");
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // Like running out of file descriptors, which shouldn't lock out ctl for good
+            Err(error) => {
+                error!(%error, "Could not accept admin connection");
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let context = context.clone();
+
+        spawn(async move {
+            if let Err(error) = handle_client(context, stream).await {
+                warn!(%error, "Admin client failed");
+            }
+        });
+    }
+}
+
+pub async fn cleanup(path: &Path) {
+    let _ = remove_stale_socket(path).await;
+}
+
+pub async fn request(path: &Path, request: &Request) -> Result<Value> {
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+    let mut line = to_string(request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    let mut lines = BufReader::new(read).lines();
+    let response = lines.next_line().await?.ok_or(Error::NoResponse)?;
+    let response: Response = from_str(&response)?;
+
+    match response.ok {
+        true => Ok(response.result.unwrap_or_default()),
+        false => Err(anyhow!(response.error.unwrap_or_default())),
+    }
+}
@@ -0,0 +1,53 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("authentication required")]
+    Required,
+    #[error("invalid credentials for {0}")]
+    InvalidCredentials(String),
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    pub fn new(username: String, password: String) -> Self {
+        Self {
+            username,
+            password,
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    // The token of a "Basic" HTTP authorization header
+    pub fn to_basic(&self) -> String {
+        STANDARD.encode(format!("{}:{}", self.username, self.password))
+    }
+
+    pub fn from_basic(token: &str) -> Option<Self> {
+        let decoded = String::from_utf8(STANDARD.decode(token).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Self::new(username.to_owned(), password.to_owned()))
+    }
+}
+
+// Keeps passwords out of logs
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Credentials").field("username", &self.username).finish_non_exhaustive()
+    }
+}
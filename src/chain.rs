@@ -0,0 +1,290 @@
+use crate::hop::{BoxedStream, Command, Hop, Target};
+use crate::resolver::Resolver;
+use anyhow::{Error as AnyError, Result};
+use futures::FutureExt;
+use serde::Deserialize;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("empty chain")]
+    EmptyChain,
+    #[error("hop {} ({hop}) failed", .index + 1)]
+    HopFailed { index: usize, hop: String },
+    #[error("{0} is a Unix socket and can only be the first hop")]
+    UnixHopNotFirst(String),
+}
+
+// Pipelined sends every request in one go and is the fastest, stepwise waits for each reply and
+// works with proxies that do not tolerate data arriving before they answered
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HandshakeMode {
+    #[default]
+    Pipelined,
+    Stepwise,
+}
+
+// A chain negotiated up to its last hop, which waits for the command meant for it
+pub struct Tunnel {
+    connector: ChainConnector,
+    stream: BoxedStream,
+    prepared: Instant,
+}
+
+// Told about every hop whose part of the handshake could be attributed
+pub trait HandshakeObserver: Send + Sync {
+    fn handshake_succeeded(&self, hop: &dyn Hop, duration: Duration);
+    fn handshake_failed(&self, hop: &dyn Hop);
+}
+
+#[derive(Clone)]
+pub struct ChainConnector {
+    hops: Vec<Arc<dyn Hop>>,
+    mode: HandshakeMode,
+    observer: Option<Arc<dyn HandshakeObserver>>,
+    resolver: Option<Arc<Resolver>>,
+}
+
+impl ChainConnector {
+    pub fn new(hops: Vec<Arc<dyn Hop>>) -> Result<Self> {
+        if hops.is_empty() {
+            return Err(Error::EmptyChain)?;
+        }
+
+        if let Some(hop) = hops.iter().skip(1).find(|hop| hop.endpoint().target().is_none()) {
+            Err(Error::UnixHopNotFirst(hop.to_string()))?;
+        }
+
+        Ok(Self {
+            hops,
+            mode: HandshakeMode::default(),
+            observer: None,
+            resolver: None,
+        })
+    }
+
+    pub fn with_mode(mut self, mode: HandshakeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn HandshakeObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    // Shares cached lookups of the first hop's hostname, a resolver of its own is made otherwise
+    pub fn with_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn hops(&self) -> &[Arc<dyn Hop>] {
+        &self.hops
+    }
+
+    fn succeeded(&self, hop: &dyn Hop, duration: Duration) {
+        if let Some(observer) = &self.observer {
+            observer.handshake_succeeded(hop, duration);
+        }
+    }
+
+    fn failed(&self, hop: &dyn Hop) {
+        if let Some(observer) = &self.observer {
+            observer.handshake_failed(hop);
+        }
+    }
+
+    // Each hop is asked to connect to the next one, the last hop gets the actual command
+    fn command_for(&self, index: usize, command: &Command) -> Command {
+        match self.hops.get(index + 1) {
+            // Checked not to be a Unix socket in new()
+            Some(next) => Command::Connect(next.endpoint().target().unwrap()),
+            None => command.clone(),
+        }
+    }
+
+    // A hop that refuses the request is reporting that it could not reach the next one, so that
+    // one is blamed instead; a refusal from the last hop is about the destination and says
+    // nothing about the chain. Anything else is the replying hop's own fault.
+    fn blame(&self, index: usize, error: AnyError) -> AnyError {
+        let hop = self.hops[index].as_ref();
+
+        if !hop.is_refusal(&error) {
+            self.failed(hop);
+        } else if let Some(next) = self.hops.get(index + 1) {
+            self.failed(next.as_ref());
+        }
+
+        error.context(Error::HopFailed {
+            index,
+            hop: hop.to_string(),
+        })
+    }
+
+    fn settle(
+        &self,
+        index: usize,
+        result: Result<SocketAddrV4>,
+        last: &mut Instant,
+    ) -> Result<SocketAddrV4> {
+        match result {
+            Ok(reply) => {
+                let now = Instant::now();
+                self.succeeded(self.hops[index].as_ref(), now - *last);
+                *last = now;
+                Ok(reply)
+            }
+            Err(error) => Err(self.blame(index, error)),
+        }
+    }
+
+    // Called once the tunnel has reached the hop, e.g. to start TLS with it
+    async fn wrap(&self, index: usize, stream: BoxedStream) -> Result<BoxedStream> {
+        let hop = self.hops[index].as_ref();
+
+        if !hop.wraps_stream() {
+            return Ok(stream);
+        }
+
+        hop.wrap(stream).await.map_err(|error| self.blame(index, error))
+    }
+
+    // Requests are written in as few batches as possible, then the replies are read back in
+    // order. A hop that wraps the stream starts a new batch, since nothing for it can be sent
+    // before the tunnel to it is up. Only the first count hops are negotiated.
+    async fn open_pipelined(
+        &self,
+        mut stream: BoxedStream,
+        command: &Command,
+        count: usize,
+    ) -> Result<(BoxedStream, Option<SocketAddrV4>)> {
+        let mut last = Instant::now();
+        let mut bound = None;
+        let mut start = 0;
+
+        while start < count {
+            let end =
+                (start + 1..count).find(|index| self.hops[*index].wraps_stream()).unwrap_or(count);
+
+            stream = self.wrap(start, stream).await?;
+            let mut buf = vec![];
+
+            for index in start..end {
+                self.hops[index].write_request(&mut buf, &self.command_for(index, command)).await?;
+            }
+
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+
+            for index in start..end {
+                let reply = self.hops[index].read_reply(&mut stream).await;
+                bound = Some(self.settle(index, reply, &mut last)?);
+            }
+
+            start = end;
+        }
+
+        Ok((stream, bound))
+    }
+
+    // Every hop gets its request only once the previous one has answered
+    async fn open_stepwise(
+        &self,
+        mut stream: BoxedStream,
+        command: &Command,
+        count: usize,
+    ) -> Result<(BoxedStream, Option<SocketAddrV4>)> {
+        let mut last = Instant::now();
+        let mut bound = None;
+
+        for (index, hop) in self.hops[..count].iter().enumerate() {
+            stream = self.wrap(index, stream).await?;
+            let reply = hop.negotiate(&mut stream, &self.command_for(index, command)).await;
+            bound = Some(self.settle(index, reply, &mut last)?);
+        }
+
+        Ok((stream, bound))
+    }
+
+    // Connects to the first hop and negotiates the first count hops
+    async fn open_hops(
+        &self,
+        command: &Command,
+        count: usize,
+    ) -> Result<(BoxedStream, Option<SocketAddrV4>)> {
+        let first = self.hops[0].as_ref();
+        // Cheap to make, it only reads the system config once there is a hostname to look up
+        let resolver = self.resolver.clone().unwrap_or_default();
+
+        let stream = match first.connect(&resolver).await {
+            Ok(stream) => stream,
+            Err(error) => return Err(self.blame(0, error)),
+        };
+
+        match self.mode {
+            HandshakeMode::Pipelined => self.open_pipelined(stream, command, count).await,
+            HandshakeMode::Stepwise => self.open_stepwise(stream, command, count).await,
+        }
+    }
+
+    // Returns the stream to the target and the address the last hop reported as bound
+    pub async fn open(&self, command: &Command) -> Result<(BoxedStream, SocketAddrV4)> {
+        let (stream, bound) = self.open_hops(command, self.hops.len()).await?;
+        Ok((stream, bound.unwrap()))
+    }
+
+    // Builds the chain up to the last hop and gets through the part of its handshake that doesn't
+    // depend on the command, so finishing it is a single round trip
+    pub async fn prepare(&self) -> Result<Tunnel> {
+        let index = self.hops.len() - 1;
+
+        // Only the last hop would get the command, the ones before it are told to connect to the
+        // next one
+        let command = Command::Connect(Target::Addr(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)));
+
+        let (stream, _) = self.open_hops(&command, index).await?;
+        let mut stream = self.wrap(index, stream).await?;
+
+        if let Err(error) = self.hops[index].greet(&mut stream).await {
+            return Err(self.blame(index, error));
+        }
+
+        Ok(Tunnel {
+            connector: self.clone(),
+            stream,
+            prepared: Instant::now(),
+        })
+    }
+
+    pub async fn connect(&self, target: SocketAddrV4) -> Result<(BoxedStream, SocketAddrV4)> {
+        self.open(&Command::Connect(target.into())).await
+    }
+}
+
+impl Tunnel {
+    pub fn age(&self) -> Duration {
+        self.prepared.elapsed()
+    }
+
+    // The last hop is waiting for the request and has nothing left to say, so anything to read,
+    // even the end of the stream, means the tunnel broke down while it waited
+    pub fn is_alive(&mut self) -> bool {
+        let mut buf = [0; 1];
+        self.stream.read(&mut buf).now_or_never().is_none()
+    }
+
+    pub async fn finish(mut self, command: &Command) -> Result<(BoxedStream, SocketAddrV4)> {
+        let index = self.connector.hops.len() - 1;
+        let hop = self.connector.hops[index].clone();
+        let mut last = Instant::now();
+        let reply = hop.finish_negotiation(&mut self.stream, command).await;
+        let bound = self.connector.settle(index, reply, &mut last)?;
+        Ok((self.stream, bound))
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Outcome of trying to decode a frame from the start of a buffer. Incomplete carries how many
+// more bytes are needed at the very least, so a reader can fetch exactly that many and never
+// consume anything past the end of the frame.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Decoded<T> {
+    Complete(T, usize),
+    Incomplete(usize),
+}
+
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>>;
+}
+
+// How many bytes buf is short of len, if any
+pub fn require(buf: &[u8], len: usize) -> Option<usize> {
+    if buf.len() < len {
+        Some(len - buf.len())
+    } else {
+        None
+    }
+}
+
+pub async fn write_frame<T, S>(stream: &mut S, frame: &T) -> Result<()>
+where
+    T: Encode,
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![];
+    frame.encode(&mut buf);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame<T, S>(stream: &mut S) -> Result<T>
+where
+    T: Decode,
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_frame_after(stream, &[]).await
+}
+
+// For frames whose leading bytes were already consumed, e.g. the version byte the server reads
+// to pick a protocol
+pub async fn read_frame_after<T, S>(stream: &mut S, prefix: &[u8]) -> Result<T>
+where
+    T: Decode,
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = prefix.to_vec();
+
+    loop {
+        match T::decode(&buf)? {
+            Decoded::Complete(frame, _) => return Ok(frame),
+            Decoded::Incomplete(needed) => {
+                let len = buf.len();
+                buf.resize(len + needed, 0);
+                stream.read_exact(&mut buf[len..]).await?;
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::metrics::Metrics;
+use crate::proxies::Proxies;
+use crate::registry::SessionRegistry;
+use crate::reload::Reloader;
+use crate::resolver::Resolver;
+use crate::selector::Selector;
+use crate::warm_pool::WarmPool;
+use std::sync::Arc;
+
+// Everything sessions and the admin socket share for the lifetime of the process
+#[derive(Clone)]
+pub struct Context {
+    reloader: Reloader,
+    metrics: Arc<Metrics>,
+    proxies: Arc<Proxies>,
+    selector: Arc<Selector>,
+    sessions: Arc<SessionRegistry>,
+    resolver: Arc<Resolver>,
+    warm_pool: Arc<WarmPool>,
+}
+
+impl Context {
+    pub fn new(reloader: Reloader) -> Self {
+        let metrics = Arc::new(Metrics::new());
+        let proxies = Arc::new(Proxies::new(metrics.clone(), reloader.subscribe()));
+        let selector = Arc::new(Selector::new(proxies.clone()));
+        let resolver = Arc::new(Resolver::new(reloader.subscribe().borrow().dns()));
+
+        Self {
+            reloader,
+            metrics,
+            proxies,
+            selector,
+            sessions: Default::default(),
+            resolver,
+            warm_pool: Default::default(),
+        }
+    }
+
+    pub fn reloader(&self) -> &Reloader {
+        &self.reloader
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn proxies(&self) -> &Arc<Proxies> {
+        &self.proxies
+    }
+
+    pub fn selector(&self) -> &Selector {
+        &self.selector
+    }
+
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
+    }
+
+    pub fn resolver(&self) -> &Arc<Resolver> {
+        &self.resolver
+    }
+
+    pub fn warm_pool(&self) -> &WarmPool {
+        &self.warm_pool
+    }
+}
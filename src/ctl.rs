@@ -0,0 +1,78 @@
+use crate::admin::{request, Mark, Request};
+use crate::config::Config;
+use anyhow::Result;
+use serde_json::{to_string_pretty, Value};
+use std::path::PathBuf;
+use thiserror::Error;
+
+pub const USAGE: &str = "\
+usage: rproxychainsd ctl [--socket PATH] COMMAND
+
+commands:
+    sessions                    list active sessions and their chains
+    kill ID                     close a session
+    mark PROXY up|down|auto     force a proxy's health, auto clears the mark
+    rotate                      drop the shared chain so the next session picks a new one
+    reload                      re-read the config file
+    stats                       dump per-proxy handshake statistics
+
+The socket defaults to [admin] socket from the config file ($CONFIG or config.toml).";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid arguments\n\n{USAGE}")]
+    Usage,
+    #[error("no [admin] socket configured in '{0}', pass --socket")]
+    NoSocket(String),
+}
+
+fn parse_request(args: &[String]) -> Result<Request> {
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+
+    let request = match args.as_slice() {
+        ["sessions"] => Request::Sessions,
+        ["kill", id] => Request::Kill {
+            id: id.parse()?,
+        },
+        ["mark", proxy, state] => Request::Mark {
+            proxy: proxy.to_string(),
+            state: match *state {
+                "up" => Mark::Up,
+                "down" => Mark::Down,
+                "auto" => Mark::Auto,
+                _ => return Err(Error::Usage)?,
+            },
+        },
+        ["rotate"] => Request::Rotate,
+        ["reload"] => Request::Reload,
+        ["stats"] => Request::Stats,
+        _ => return Err(Error::Usage)?,
+    };
+
+    Ok(request)
+}
+
+async fn socket_from_config(config_file: &str) -> Result<PathBuf> {
+    let config = Config::read_file(config_file).await?;
+
+    match config.admin() {
+        Some(admin) => Ok(admin.socket().to_owned()),
+        None => Err(Error::NoSocket(config_file.to_owned()))?,
+    }
+}
+
+pub async fn run(config_file: &str, args: &[String]) -> Result<()> {
+    let (socket, args) = match args {
+        [flag, socket, rest @ ..] if flag == "--socket" => (PathBuf::from(socket), rest),
+        _ => (socket_from_config(config_file).await?, args),
+    };
+
+    let request = parse_request(args)?;
+
+    match self::request(&socket, &request).await? {
+        Value::Null => println!("ok"),
+        value => println!("{}", to_string_pretty(&value)?),
+    }
+
+    Ok(())
+}
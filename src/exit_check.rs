@@ -0,0 +1,391 @@
+use crate::chain::ChainConnector;
+use crate::config::{Config, Profile, Proxy};
+use crate::context::Context;
+use crate::hop::{BoxedStream, Command, Endpoint, Target};
+use crate::reload::Reloader;
+use anyhow::{Error as AnyError, Result};
+use futures::stream::{iter, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string_pretty;
+use std::collections::{BTreeMap, HashSet};
+use std::future::pending;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::select;
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+
+// Responses to "what is my IP" requests are short, anything past this is cut off
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+// Proxies checked at the same time
+const CONCURRENCY: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("usage: rproxychainsd check")]
+    Usage,
+    #[error("exit checks need an http:// URL, not {0}")]
+    UnsupportedUrl(String),
+    #[error("no [exit_check] in {0}")]
+    NotConfigured(String),
+    #[error("unexpected response {0:?}")]
+    UnexpectedResponse(String),
+    #[error("no IPv4 address in the response")]
+    NoAddress,
+    #[error("timed out")]
+    TimedOut,
+    #[error("{0} of {1} proxies failed the exit check")]
+    Flagged(usize, usize),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExitCheckOptions {
+    url: String,
+    interval: Option<u64>,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+// The options as written plus where the URL points, which is checked when the config is loaded
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "ExitCheckOptions")]
+pub struct ExitCheck {
+    options: ExitCheckOptions,
+    target: Target,
+    host: String,
+    path: String,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Ok,
+    // The exit address is our own, the chain hides nothing
+    Transparent,
+    // The exit is elsewhere, but our own address shows up in the response too, e.g. passed on
+    // in X-Forwarded-For by a proxy along the way
+    Leaking,
+    // The exit is in another country than the proxy is tagged with
+    Misplaced,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExitReport {
+    verdict: Verdict,
+    exit: Option<Ipv4Addr>,
+    country: Option<String>,
+    error: Option<String>,
+    checked_at: u64,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl ExitCheckOptions {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval.map(Duration::from_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl ExitReport {
+    fn new(verdict: Verdict, exit: Option<Ipv4Addr>, country: Option<String>) -> Self {
+        let checked_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Self {
+            verdict,
+            exit,
+            country,
+            error: None,
+            checked_at: checked_at.as_secs(),
+        }
+    }
+
+    fn failed(error: &AnyError) -> Self {
+        Self {
+            error: Some(format!("{:#}", error)),
+            ..Self::new(Verdict::Failed, None, None)
+        }
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+
+    pub fn exit(&self) -> Option<Ipv4Addr> {
+        self.exit
+    }
+
+    pub fn country(&self) -> Option<&str> {
+        self.country.as_deref()
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+impl ExitCheck {
+    pub fn options(&self) -> &ExitCheckOptions {
+        &self.options
+    }
+
+    // Every proxy of every chain is checked once, as the exit of a chain picked up to it, so
+    // proxies that are only reachable through others are checked too. Results are recorded with
+    // the proxy's stats.
+    pub async fn check_all(&self, context: &Context, config: &Config) -> Vec<(String, ExitReport)> {
+        let direct = match timeout(self.options.timeout(), self.direct(context)).await {
+            Ok(Ok(direct)) => Some(direct),
+            Ok(Err(error)) => {
+                warn!(error = format!("{:#}", error), "Could not look up our own address");
+                None
+            }
+            Err(_) => {
+                warn!("Timed out looking up our own address");
+                None
+            }
+        };
+
+        let mut seen = HashSet::new();
+        let mut chains = Vec::new();
+        let mut reports = Vec::new();
+        let profiles = config.profiles().values().map(Profile::chains);
+
+        for chain_set in [config.chains()].into_iter().chain(profiles) {
+            for (index, chain) in chain_set.iter().enumerate() {
+                for proxy in chain.entries().iter() {
+                    if !seen.insert(proxy.to_string()) {
+                        continue;
+                    }
+
+                    match context.selector().make_chain_to(chain_set, index, proxy) {
+                        Ok(chain) => chains.push(chain),
+                        Err(error) => reports.push((proxy.to_string(), ExitReport::failed(&error))),
+                    }
+                }
+            }
+        }
+
+        let checks = iter(chains)
+            .map(|chain| async move {
+                let proxy = chain.last().unwrap().clone();
+                (proxy.to_string(), self.check(context, config, chain, &proxy, direct).await)
+            })
+            .buffer_unordered(CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+        reports.extend(checks);
+        reports.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        for (proxy, report) in &reports {
+            context.proxies().set_exit(proxy, report.clone());
+
+            if report.verdict != Verdict::Ok {
+                warn!(
+                    proxy,
+                    verdict = ?report.verdict,
+                    exit = ?report.exit,
+                    error = report.error,
+                    "Proxy failed the exit check"
+                );
+            }
+        }
+
+        reports
+    }
+
+    async fn check(
+        &self,
+        context: &Context,
+        config: &Config,
+        chain: Vec<Proxy>,
+        proxy: &Proxy,
+        direct: Option<Ipv4Addr>,
+    ) -> ExitReport {
+        let addresses =
+            match timeout(self.options.timeout(), self.through(context, config, chain)).await {
+                Ok(Ok(addresses)) => addresses,
+                Ok(Err(error)) => return ExitReport::failed(&error),
+                Err(_) => return ExitReport::failed(&Error::TimedOut.into()),
+            };
+
+        // Proxies that pass on where connections come from add addresses in front of the exit
+        let exit = match addresses.last() {
+            Some(exit) => *exit,
+            None => return ExitReport::failed(&Error::NoAddress.into()),
+        };
+
+        let country = config.geoip().and_then(|geoip| geoip.country(exit));
+
+        let verdict = if Some(exit) == direct {
+            Verdict::Transparent
+        } else if direct.is_some_and(|direct| addresses.contains(&direct)) {
+            Verdict::Leaking
+        } else if proxy
+            .tag("country")
+            .zip(country.as_deref())
+            .is_some_and(|(claimed, actual)| claimed != actual)
+        {
+            Verdict::Misplaced
+        } else {
+            Verdict::Ok
+        };
+
+        ExitReport::new(verdict, Some(exit), country)
+    }
+
+    async fn through(
+        &self,
+        context: &Context,
+        config: &Config,
+        chain: Vec<Proxy>,
+    ) -> Result<Vec<Ipv4Addr>> {
+        let hops = chain.iter().map(Proxy::hop).collect();
+        let connector = ChainConnector::new(hops)?
+            .with_mode(config.server().handshake())
+            .with_observer(context.proxies().clone())
+            .with_resolver(context.resolver().clone());
+
+        let (stream, _) = connector.open(&Command::Connect(self.target.clone())).await?;
+        self.fetch(stream).await
+    }
+
+    // Our own address as the endpoint sees it without a chain, to tell leaks by
+    async fn direct(&self, context: &Context) -> Result<Ipv4Addr> {
+        let endpoint = match &self.target {
+            Target::Addr(addr) => Endpoint::Tcp(*addr),
+            Target::Domain(host, port) => Endpoint::Host(host.clone(), *port),
+        };
+
+        let stream = endpoint.connect(context.resolver()).await?;
+        let addresses = self.fetch(stream).await?;
+        Ok(*addresses.last().ok_or(Error::NoAddress)?)
+    }
+
+    // Every IPv4 address in the body, whether it is plain text, JSON or HTML
+    async fn fetch(&self, mut stream: BoxedStream) -> Result<Vec<Ipv4Addr>> {
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rproxychainsd\r\nAccept: */*\r\n\r\n",
+            self.path, self.host
+        );
+
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head.lines().next().unwrap_or_default();
+
+        if status.split(' ').nth(1) != Some("200") {
+            Err(Error::UnexpectedResponse(status.to_owned()))?;
+        }
+
+        let words = body.split(|c: char| !c.is_ascii_digit() && c != '.');
+        Ok(words.filter_map(|word| word.parse().ok()).collect())
+    }
+}
+
+impl TryFrom<ExitCheckOptions> for ExitCheck {
+    type Error = Error;
+
+    fn try_from(options: ExitCheckOptions) -> Result<Self, Self::Error> {
+        let unsupported = || Error::UnsupportedUrl(options.url.clone());
+        let rest = options.url.strip_prefix("http://").ok_or_else(unsupported)?;
+
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) => (name, port.parse().map_err(|_| unsupported())?),
+            None => (host, 80),
+        };
+
+        if name.is_empty() {
+            Err(unsupported())?;
+        }
+
+        let target = match name.parse() {
+            Ok(ip) => Target::Addr(SocketAddrV4::new(ip, port)),
+            Err(_) => Target::Domain(name.to_owned(), port),
+        };
+
+        Ok(Self {
+            target,
+            host: host.to_owned(),
+            path: path.to_owned(),
+            options,
+        })
+    }
+}
+
+// Runs the checks every [exit_check] interval, following config reloads
+pub async fn check_periodically(context: Context) {
+    let mut configs = context.reloader().subscribe();
+
+    loop {
+        let config = configs.borrow_and_update().clone();
+        let check = config.exit_check().cloned();
+
+        let wait = async {
+            match check.as_ref().and_then(|check| check.options.interval()) {
+                Some(interval) => sleep(interval).await,
+                None => pending().await,
+            }
+        };
+
+        select! {
+            changed = configs.changed() => match changed {
+                Ok(()) => continue,
+                Err(_) => return,
+            },
+            () = wait => {}
+        }
+
+        if let Some(check) = check {
+            let reports = check.check_all(&context, &config).await;
+            let flagged = reports.iter().filter(|(_, report)| report.verdict != Verdict::Ok);
+            info!(proxies = reports.len(), flagged = flagged.count(), "Exit check finished");
+        }
+    }
+}
+
+pub async fn run(config_file: &str, args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        Err(Error::Usage)?;
+    }
+
+    let config = Config::read_file(config_file).await?;
+    let check =
+        config.exit_check().cloned().ok_or_else(|| Error::NotConfigured(config_file.to_owned()))?;
+    let context = Context::new(Reloader::new(config_file, config));
+    let config = context.reloader().subscribe().borrow().clone();
+
+    let reports = check.check_all(&context, &config).await;
+    let flagged = reports.iter().filter(|(_, report)| report.verdict != Verdict::Ok).count();
+    let total = reports.len();
+
+    println!("{}", to_string_pretty(&reports.into_iter().collect::<BTreeMap<_, _>>())?);
+
+    if flagged > 0 {
+        Err(Error::Flagged(flagged, total))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use crate::config::Proxy;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::iter::Peekable;
+use std::str::FromStr;
+use std::vec::IntoIter;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unexpected {0} in filter")]
+    UnexpectedToken(String),
+    #[error("filter ends too early")]
+    UnexpectedEnd,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Word(String),
+    Equals,
+    NotEquals,
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Has(String),
+    Equals(String, String),
+    In(String, Vec<String>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+// An expression over proxy tags, like "country in [de, nl] and not residential". A tag is either
+// a bare label or key=value, "key" on its own matches both. "not" binds tighter than "and", which
+// binds tighter than "or".
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct Filter {
+    text: String,
+    expr: Expr,
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+}
+
+impl Filter {
+    pub fn matches(&self, proxy: &Proxy) -> bool {
+        self.expr.matches(proxy)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?.into_iter().peekable(),
+        };
+
+        let expr = parser.or()?;
+
+        if let Some(token) = parser.tokens.next() {
+            Err(Error::UnexpectedToken(token.to_string()))?;
+        }
+
+        Ok(Self {
+            text: s.to_owned(),
+            expr,
+        })
+    }
+}
+
+impl TryFrom<String> for Filter {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Word(word) => write!(f, "{:?}", word),
+            Self::Equals => write!(f, "\"=\""),
+            Self::NotEquals => write!(f, "\"!=\""),
+            Self::Open => write!(f, "\"(\""),
+            Self::Close => write!(f, "\")\""),
+            Self::OpenList => write!(f, "\"[\""),
+            Self::CloseList => write!(f, "\"]\""),
+            Self::Comma => write!(f, "\",\""),
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, proxy: &Proxy) -> bool {
+        match self {
+            Self::Has(key) => proxy.tag(key).is_some(),
+            Self::Equals(key, value) => proxy.tag(key) == Some(value),
+            Self::In(key, values) => {
+                proxy.tag(key).is_some_and(|tag| values.iter().any(|value| value == tag))
+            }
+            Self::Not(expr) => !expr.matches(proxy),
+            Self::And(left, right) => left.matches(proxy) && right.matches(proxy),
+            Self::Or(left, right) => left.matches(proxy) || right.matches(proxy),
+        }
+    }
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<Token, Error> {
+        self.tokens.next().ok_or(Error::UnexpectedEnd)
+    }
+
+    fn next_if_word(&mut self, keyword: &str) -> bool {
+        self.tokens.next_if(|token| matches!(token, Token::Word(word) if word == keyword)).is_some()
+    }
+
+    fn word(&mut self) -> Result<String, Error> {
+        match self.next()? {
+            Token::Word(word) if !is_keyword(&word) => Ok(word),
+            token => Err(Error::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Error> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(Error::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.and()?;
+
+        while self.next_if_word("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.not()?;
+
+        while self.next_if_word("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, Error> {
+        if self.next_if_word("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Expr, Error> {
+        if self.tokens.next_if_eq(&Token::Open).is_some() {
+            let expr = self.or()?;
+            self.expect(Token::Close)?;
+            return Ok(expr);
+        }
+
+        let key = self.word()?;
+
+        if self.tokens.next_if_eq(&Token::Equals).is_some() {
+            Ok(Expr::Equals(key, self.word()?))
+        } else if self.tokens.next_if_eq(&Token::NotEquals).is_some() {
+            Ok(Expr::Not(Box::new(Expr::Equals(key, self.word()?))))
+        } else if self.next_if_word("in") {
+            self.expect(Token::OpenList)?;
+            let mut values = vec![self.word()?];
+
+            while self.tokens.next_if_eq(&Token::Comma).is_some() {
+                values.push(self.word()?);
+            }
+
+            self.expect(Token::CloseList)?;
+            Ok(Expr::In(key, values))
+        } else {
+            Ok(Expr::Has(key))
+        }
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    ["and", "or", "not", "in"].contains(&word)
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '=' => Token::Equals,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::NotEquals,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '[' => Token::OpenList,
+            ']' => Token::CloseList,
+            ',' => Token::Comma,
+            c if c.is_whitespace() => continue,
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+
+                while let Some(c) = chars.next_if(|c| is_word_char(*c)) {
+                    word.push(c);
+                }
+
+                Token::Word(word)
+            }
+            c => Err(Error::UnexpectedToken(format!("{:?}", c)))?,
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || ['_', '-', '.', ':', '/'].contains(&c)
+}
@@ -0,0 +1,110 @@
+use crate::config::Proxy;
+use crate::hop::Endpoint;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not read GeoIP database {0}: {1}")]
+    Unreadable(String, MaxMindDBError),
+    #[error("[geoip] needs a country or asn database")]
+    NoDatabase,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpOptions {
+    country: Option<PathBuf>,
+    asn: Option<PathBuf>,
+}
+
+// The options as written plus the databases, which are read into memory when the config is
+// loaded. Lookups never leave the process.
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "GeoIpOptions")]
+pub struct GeoIp {
+    options: GeoIpOptions,
+    country: Option<Arc<Reader<Vec<u8>>>>,
+    asn: Option<Arc<Reader<Vec<u8>>>>,
+}
+
+impl GeoIpOptions {
+    pub fn country(&self) -> Option<&Path> {
+        self.country.as_deref()
+    }
+
+    pub fn asn(&self) -> Option<&Path> {
+        self.asn.as_deref()
+    }
+}
+
+impl GeoIp {
+    pub fn options(&self) -> &GeoIpOptions {
+        &self.options
+    }
+
+    // Lower case ISO code, like the country tags written by hand
+    pub fn country(&self, ip: Ipv4Addr) -> Option<String> {
+        let country: geoip2::Country = self.country.as_ref()?.lookup(IpAddr::V4(ip)).ok()?;
+        Some(country.country?.iso_code?.to_lowercase())
+    }
+
+    pub fn asn(&self, ip: Ipv4Addr) -> Option<u32> {
+        let asn: geoip2::Asn = self.asn.as_ref()?.lookup(IpAddr::V4(ip)).ok()?;
+        asn.autonomous_system_number
+    }
+
+    // Adds country=xx and asn=N tags to proxies given by address. Tags the proxy already has
+    // win, so a wrong database entry can be corrected in the config.
+    pub fn annotate(&self, proxy: Proxy) -> Proxy {
+        let ip = match proxy.endpoint() {
+            Endpoint::Tcp(addr) => *addr.ip(),
+            Endpoint::Host(..) | Endpoint::Unix(_) => return proxy,
+        };
+
+        let mut tags = Vec::new();
+
+        if let (None, Some(country)) = (proxy.tag("country"), self.country(ip)) {
+            tags.push(format!("country={}", country));
+        }
+
+        if let (None, Some(asn)) = (proxy.tag("asn"), self.asn(ip)) {
+            tags.push(format!("asn={}", asn));
+        }
+
+        tags.into_iter().fold(proxy, Proxy::with_tag)
+    }
+}
+
+impl TryFrom<GeoIpOptions> for GeoIp {
+    type Error = Error;
+
+    fn try_from(options: GeoIpOptions) -> Result<Self, Self::Error> {
+        if options.country.is_none() && options.asn.is_none() {
+            Err(Error::NoDatabase)?;
+        }
+
+        let open = |path: &Path| {
+            Reader::open_readfile(path)
+                .map(Arc::new)
+                .map_err(|error| Error::Unreadable(path.display().to_string(), error))
+        };
+
+        Ok(Self {
+            country: options.country().map(open).transpose()?,
+            asn: options.asn().map(open).transpose()?,
+            options,
+        })
+    }
+}
+
+impl Debug for GeoIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        self.options.fmt(f)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::resolver::Resolver;
+use anyhow::{Error as AnyError, Result};
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+pub type HopReader<'a> = dyn AsyncRead + Unpin + Send + 'a;
+pub type HopWriter<'a> = dyn AsyncWrite + Unpin + Send + 'a;
+pub type HopStream<'a> = dyn ReadWrite + Unpin + Send + 'a;
+pub type BoxedStream = Box<HopStream<'static>>;
+
+pub trait ReadWrite: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite + ?Sized> ReadWrite for T {}
+
+// What a hop is asked to connect to, a hostname is left for the proxy to resolve
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Target {
+    Addr(SocketAddrV4),
+    Domain(String, u16),
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Command {
+    Connect(Target),
+    Bind(SocketAddrV4),
+}
+
+// Where a hop is reached. Hops after the first are reached through the tunnel, which only
+// leads to network addresses and hostnames, so only the first one can be a Unix socket. A
+// hostname is resolved locally for the first hop and by the previous proxy otherwise.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Endpoint {
+    Tcp(SocketAddrV4),
+    Host(String, u16),
+    Unix(PathBuf),
+}
+
+// One proxy in a chain. The previous hop (or the connector itself, for the first one) dials
+// endpoint(), then this hop is asked to tunnel to whatever comes after it. Display is the
+// proxy's identity in logs, metrics and health tracking, e.g. socks5://127.0.0.1:1080.
+#[async_trait]
+pub trait Hop: Display + Send + Sync {
+    fn endpoint(&self) -> &Endpoint;
+
+    // Only called on the first hop, the others are reached through the tunnel
+    async fn connect(&self, resolver: &Resolver) -> Result<BoxedStream> {
+        self.endpoint().connect(resolver).await
+    }
+
+    // Whether the stream needs an upgrade by wrap() once the tunnel reaches this hop, in which
+    // case nothing meant for this hop or the ones after it can be sent before that
+    fn wraps_stream(&self) -> bool {
+        false
+    }
+
+    async fn wrap(&self, stream: BoxedStream) -> Result<BoxedStream> {
+        Ok(stream)
+    }
+
+    async fn write_request(&self, stream: &mut HopWriter<'_>, command: &Command) -> Result<()>;
+
+    // Consumes this hop's whole reply and returns the address it reports as bound
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> Result<SocketAddrV4>;
+
+    // Stepwise counterpart of write_request and read_reply, waiting for every answer before
+    // sending anything else. Hops with several round trips, like SOCKS5 auth, override this.
+    async fn negotiate(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        let mut buf = vec![];
+        self.write_request(&mut buf, command).await?;
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+        self.read_reply(stream).await
+    }
+
+    // The part of the handshake that doesn't depend on the command, like SOCKS5 method selection
+    // and auth. Tunnels prepared ahead of time take their last hop this far, so only
+    // finish_negotiation is left once the command is known.
+    async fn greet(&self, _stream: &mut HopStream<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    // negotiate for a hop that was greeted already, a single round trip
+    async fn finish_negotiation(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        self.negotiate(stream, command).await
+    }
+
+    // Whether the hop answered properly but refused the request, i.e. it could not reach the
+    // next target, as opposed to breaking the protocol itself
+    fn is_refusal(&self, _error: &AnyError) -> bool {
+        false
+    }
+}
+
+impl Target {
+    pub fn addr(&self) -> Option<SocketAddrV4> {
+        match self {
+            Self::Addr(addr) => Some(*addr),
+            Self::Domain(..) => None,
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Addr(addr) => write!(f, "{}", addr),
+            Self::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl From<SocketAddrV4> for Target {
+    fn from(value: SocketAddrV4) -> Self {
+        Self::Addr(value)
+    }
+}
+
+impl Endpoint {
+    // What the previous hop is asked to connect to, None for Unix sockets
+    pub fn target(&self) -> Option<Target> {
+        match self {
+            Self::Tcp(addr) => Some(Target::Addr(*addr)),
+            Self::Host(host, port) => Some(Target::Domain(host.clone(), *port)),
+            Self::Unix(_) => None,
+        }
+    }
+
+    // Every address a hostname resolves to is tried in turn
+    pub async fn connect(&self, resolver: &Resolver) -> Result<BoxedStream> {
+        let addrs = match self {
+            Self::Tcp(addr) => vec![*addr],
+            Self::Host(host, port) => resolver.resolve(host, *port).await?,
+            Self::Unix(path) => return Ok(Box::new(UnixStream::connect(path).await?)),
+        };
+
+        let mut last_error = None;
+
+        for addr in addrs {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        // Resolving never returns an empty list
+        Err(last_error.unwrap())?
+    }
+}
+
+// Unix sockets are written the way Tor does, e.g. unix:/run/tor/socks
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Host(host, port) => write!(f, "{}:{}", host, port),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddrV4> for Endpoint {
+    fn from(value: SocketAddrV4) -> Self {
+        Self::Tcp(value)
+    }
+}
@@ -0,0 +1,258 @@
+use crate::auth::Credentials;
+use crate::codec::{read_frame, write_frame, Decode, Decoded, Encode};
+use crate::hop::{Command, Endpoint, Hop, HopReader, HopWriter, Target};
+use anyhow::{Error as AnyError, Result};
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use thiserror::Error;
+
+const MAX_HEADER: usize = 8192;
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("request failed: {0}")]
+    RequestFailed(u16),
+    #[error("malformed response")]
+    Malformed,
+    #[error("response header too long")]
+    HeaderTooLong,
+    #[error("only CONNECT is supported")]
+    UnsupportedCommand,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct HttpConnectRequest {
+    target: Target,
+    credentials: Option<Credentials>,
+}
+
+// Only the status is kept, the headers of a successful CONNECT carry nothing we use. Replies we
+// send may ask for credentials.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct HttpConnectReply {
+    status: u16,
+    challenge: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct HttpHop {
+    endpoint: Endpoint,
+    credentials: Option<Credentials>,
+}
+
+impl HttpConnectRequest {
+    pub fn new(target: impl Into<Target>) -> Self {
+        Self {
+            target: target.into(),
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+}
+
+impl Encode for HttpConnectRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", self.target);
+
+        if let Some(credentials) = &self.credentials {
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials.to_basic()));
+        }
+
+        request.push_str("\r\n");
+        buf.extend_from_slice(request.as_bytes());
+    }
+}
+
+// Headers other than Proxy-Authorization are ignored. Only address targets are taken, clients
+// resolve hostnames themselves.
+impl Decode for HttpConnectRequest {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        let len = match header_len(buf)? {
+            Some(len) => len,
+            None => return Ok(Decoded::Incomplete(missing_header_end(buf))),
+        };
+
+        let header = std::str::from_utf8(&buf[..len]).map_err(|_| Error::Malformed)?;
+        let mut lines = header.split("\r\n");
+        let mut parts = lines.next().unwrap_or_default().splitn(3, ' ');
+
+        match parts.next() {
+            Some("CONNECT") => {}
+            _ => return Err(Error::UnsupportedCommand)?,
+        }
+
+        let target: SocketAddrV4 =
+            parts.next().and_then(|target| target.parse().ok()).ok_or(Error::Malformed)?;
+
+        match parts.next() {
+            Some("HTTP/1.0" | "HTTP/1.1") => {}
+            _ => return Err(Error::Malformed)?,
+        }
+
+        let mut request = Self::new(target);
+
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            if !name.trim().eq_ignore_ascii_case("proxy-authorization") {
+                continue;
+            }
+
+            let credentials = match value.trim().split_once(' ') {
+                Some((scheme, token)) if scheme.eq_ignore_ascii_case("basic") => {
+                    Credentials::from_basic(token.trim())
+                }
+                _ => None,
+            };
+
+            request = request.with_credentials(credentials.ok_or(Error::Malformed)?);
+        }
+
+        Ok(Decoded::Complete(request, len))
+    }
+}
+
+impl HttpConnectReply {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            challenge: false,
+        }
+    }
+
+    // A 407 that tells the client to retry with Basic credentials
+    pub fn auth_required() -> Self {
+        Self {
+            status: 407,
+            challenge: true,
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+}
+
+impl Encode for HttpConnectReply {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(format!("HTTP/1.1 {} \r\n", self.status).as_bytes());
+
+        if self.challenge {
+            buf.extend_from_slice(b"Proxy-Authenticate: Basic realm=\"rproxychainsd\"\r\n");
+        }
+
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+impl Decode for HttpConnectReply {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        let len = match header_len(buf)? {
+            Some(len) => len,
+            None => return Ok(Decoded::Incomplete(missing_header_end(buf))),
+        };
+
+        let status_line = buf.split(|byte| *byte == b'\n').next().unwrap_or_default();
+        let status_line = std::str::from_utf8(status_line).map_err(|_| Error::Malformed)?;
+        let mut parts = status_line.trim_end().splitn(3, ' ');
+
+        match parts.next() {
+            Some("HTTP/1.0" | "HTTP/1.1") => {}
+            _ => return Err(Error::Malformed)?,
+        }
+
+        let status = parts.next().and_then(|status| status.parse().ok()).ok_or(Error::Malformed)?;
+
+        if !(200..300).contains(&status) {
+            return Err(Error::RequestFailed(status))?;
+        }
+
+        Ok(Decoded::Complete(Self::new(status), len))
+    }
+}
+
+// Up to and including the blank line that ends the header, once all of it is there
+fn header_len(buf: &[u8]) -> Result<Option<usize>> {
+    match buf.windows(HEADER_END.len()).position(|window| window == HEADER_END) {
+        Some(position) => Ok(Some(position + HEADER_END.len())),
+        None if buf.len() >= MAX_HEADER => Err(Error::HeaderTooLong)?,
+        None => Ok(None),
+    }
+}
+
+// The least number of bytes that could complete the header, so nothing after it gets consumed
+fn missing_header_end(buf: &[u8]) -> usize {
+    (1..HEADER_END.len())
+        .rev()
+        .find(|matched| buf.ends_with(&HEADER_END[..*matched]))
+        .map_or(HEADER_END.len(), |matched| HEADER_END.len() - matched)
+}
+
+impl HttpHop {
+    pub fn new(endpoint: impl Into<Endpoint>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+}
+
+impl Display for HttpHop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "http://{}", self.endpoint)
+    }
+}
+
+#[async_trait]
+impl Hop for HttpHop {
+    fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    async fn write_request(&self, stream: &mut HopWriter<'_>, command: &Command) -> Result<()> {
+        match command {
+            Command::Connect(target) => {
+                let mut request = HttpConnectRequest::new(target.clone());
+
+                if let Some(credentials) = &self.credentials {
+                    request = request.with_credentials(credentials.clone());
+                }
+
+                write_frame(stream, &request).await
+            }
+            Command::Bind(_) => Err(Error::UnsupportedCommand)?,
+        }
+    }
+
+    // HTTP proxies don't tell which address they connected from
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> Result<SocketAddrV4> {
+        read_frame::<HttpConnectReply, _>(stream).await?;
+        Ok(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+    }
+
+    // A 407 is about our credentials, not about the next target
+    fn is_refusal(&self, error: &AnyError) -> bool {
+        matches!(error.downcast_ref(), Some(Error::RequestFailed(status)) if *status != 407)
+    }
+}
@@ -0,0 +1,36 @@
+pub mod access_log;
+pub mod acl;
+pub mod admin;
+pub mod auth;
+pub mod chain;
+pub mod codec;
+pub mod config;
+pub mod context;
+pub mod ctl;
+pub mod exit_check;
+pub mod filter;
+pub mod geoip;
+pub mod hop;
+pub mod http;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod proxies;
+pub mod proxy_list;
+pub mod proxychains;
+pub mod registry;
+pub mod reload;
+pub mod resolver;
+pub mod selector;
+pub mod server;
+pub mod session;
+pub mod socks;
+pub mod socks4;
+pub mod socks5;
+pub mod state;
+pub mod timeout;
+pub mod tls;
+pub mod warm_pool;
+
+pub use crate::chain::{ChainConnector, HandshakeMode, HandshakeObserver};
+pub use crate::config::Proxy;
@@ -0,0 +1,113 @@
+use crate::admin::remove_stale_socket;
+use crate::hop::BoxedStream;
+use anyhow::Result;
+use serde::{Serialize, Serializer};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{remove_file, Permissions};
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use tokio::fs::set_permissions;
+use tokio::net::{TcpListener, UnixListener};
+
+// Where a listener takes connections
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ListenAddr {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+// Where a client came from. Peers on a Unix socket are usually unnamed, so they go by the path of
+// the socket they connected to.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+// A bound listener. A Unix socket file is removed again when the socket is dropped.
+pub enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Socket {
+    pub async fn bind(addr: &ListenAddr, mode: u32) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(host, port) => {
+                Ok(Self::Tcp(TcpListener::bind((host.as_str(), *port)).await?))
+            }
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path).await?;
+                let socket = Self::Unix(UnixListener::bind(path)?, path.clone());
+                socket.set_mode(mode).await?;
+                Ok(socket)
+            }
+        }
+    }
+
+    // Only applies to Unix sockets, which are the only ones with permissions
+    pub async fn set_mode(&self, mode: u32) -> Result<()> {
+        if let Self::Unix(_, path) = self {
+            set_permissions(path, Permissions::from_mode(mode)).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn accept(&self) -> IoResult<(BoxedStream, ClientAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), ClientAddr::Tcp(addr)))
+            }
+            Self::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), ClientAddr::Unix(path.clone())))
+            }
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Tcp(host, port) => write!(f, "{}:{}", host, port),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Written like the addresses were before Unix sockets, as a plain string
+impl Serialize for ClientAddr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl From<SocketAddr> for ClientAddr {
+    fn from(value: SocketAddr) -> Self {
+        Self::Tcp(value)
+    }
+}
@@ -0,0 +1,25 @@
+use crate::config::{Log, LogFormat};
+use anyhow::Result;
+use std::env;
+use std::io::{stderr, IsTerminal};
+use tracing_subscriber::fmt;
+use tracing_subscriber::EnvFilter;
+
+// RUST_LOG takes precedence over the configured level so a single run can be debugged without
+// touching the config file.
+pub fn init(config: &Log) -> Result<()> {
+    let filter = match env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(directives)?,
+        Err(_) => EnvFilter::try_new(config.level())?,
+    };
+
+    let builder =
+        fmt().with_env_filter(filter).with_writer(stderr).with_ansi(stderr().is_terminal());
+
+    match config.format() {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+
+    Ok(())
+}
@@ -1,36 +1,151 @@
-mod config;
-mod server;
-mod session;
-mod socks;
-mod socks4;
-mod socks5;
-
-use crate::config::Config;
-use crate::server::Server;
 use anyhow::Result;
+use rproxychainsd::admin::{cleanup as cleanup_admin, serve as serve_admin};
+use rproxychainsd::config::Config;
+use rproxychainsd::context::Context;
+use rproxychainsd::exit_check::{self, check_periodically};
+use rproxychainsd::metrics::serve as serve_metrics;
+use rproxychainsd::proxy_list::watch_proxy_lists;
+use rproxychainsd::reload::{reload_on_hangup, Reloader};
+use rproxychainsd::server::{Server, Shutdown};
+use rproxychainsd::state::{self, save_periodically};
+use rproxychainsd::warm_pool::keep_warm;
+use rproxychainsd::{ctl, logging, proxychains};
 use std::env;
+use std::future::Future;
+use std::process::ExitCode;
 use tokio::main;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::spawn;
+use tracing::{error, info, warn};
 
-async fn run(config: Config) -> Result<()> {
-    let server = Server::new(config.into());
-    server.run().await?;
-    Ok(())
+// The handlers are installed before this returns, so a signal arriving before the server
+// starts polling the future is not lost.
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        select! {
+            _ = terminate.recv() => info!("Received SIGTERM"),
+            _ = interrupt.recv() => info!("Received SIGINT"),
+        }
+    })
+}
+
+async fn run(reloader: Reloader) -> Result<Shutdown> {
+    let context = Context::new(reloader);
+    let hangup_reloader = context.reloader().clone();
+
+    spawn(async move {
+        if let Err(error) = reload_on_hangup(hangup_reloader).await {
+            error!(%error, "Could not install SIGHUP handler");
+        }
+    });
+
+    // A broken state file costs what it would have told us, not the start
+    if let Err(error) = state::load(&context).await {
+        warn!(%error, "Could not load proxy state");
+    }
+
+    spawn(check_periodically(context.clone()));
+    spawn(save_periodically(context.clone()));
+    spawn(keep_warm(context.clone()));
+
+    let watch_reloader = context.reloader().clone();
+
+    spawn(async move {
+        if let Err(error) = watch_proxy_lists(watch_reloader).await {
+            error!(%error, "Could not watch proxy lists");
+        }
+    });
+
+    // Both endpoints are set up once, changing their addresses requires a restart
+    let config = context.reloader().subscribe().borrow().clone();
+
+    if let Some(metrics_config) = config.metrics() {
+        let host = metrics_config.host().to_owned();
+        let port = metrics_config.port();
+        let metrics = context.metrics().clone();
+
+        spawn(async move {
+            if let Err(error) = serve_metrics(metrics, &host, port).await {
+                error!(%error, "Metrics endpoint failed");
+            }
+        });
+    }
+
+    if let Some(admin_config) = config.admin() {
+        let socket = admin_config.socket().to_owned();
+        let mode = admin_config.mode();
+        let context = context.clone();
+
+        spawn(async move {
+            if let Err(error) = serve_admin(context, &socket, mode).await {
+                error!(%error, path = %socket.display(), "Admin socket failed");
+            }
+        });
+    }
+
+    let shutdown = shutdown_signal()?;
+    let server = Server::new(context.clone());
+    let result = server.run(shutdown).await;
+
+    if let Err(error) = state::save(&context).await {
+        error!(%error, "Could not save proxy state");
+    }
+
+    if let Some(admin_config) = config.admin() {
+        cleanup_admin(admin_config.socket()).await;
+    }
+
+    result
 }
 
 #[main(flavor = "current_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let config_file = env::var("CONFIG");
     let config_file = config_file.as_ref().map(|s| s.as_str()).unwrap_or("config.toml");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let command = match args.first().map(String::as_str) {
+        Some("ctl") => Some(ctl::run(config_file, &args[1..]).await),
+        Some("import" | "export") => Some(proxychains::run(config_file, &args).await),
+        Some("check") => Some(exit_check::run(config_file, &args[1..]).await),
+        _ => None,
+    };
+
+    if let Some(result) = command {
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("{}", error);
+                ExitCode::FAILURE
+            }
+        };
+    }
 
     let config = match Config::read_file(config_file).await {
         Err(error) => {
-            eprintln!("[error] Could not read config from '{}': {}", config_file, error);
-            return;
+            // Nothing configured yet, but the error should still look like every other log line
+            let _ = logging::init(&Default::default());
+            error!(file = config_file, %error, "Could not read config");
+            return ExitCode::from(2);
         }
         Ok(config) => config,
     };
 
-    if let Err(error) = run(config).await {
-        eprintln!("[error] Fatal error: {}", error);
+    if let Err(error) = logging::init(config.log()) {
+        eprintln!("Invalid log config: {}", error);
+        return ExitCode::from(2);
+    }
+
+    match run(Reloader::new(config_file, config)).await {
+        Ok(Shutdown::Drained) => ExitCode::SUCCESS,
+        Ok(Shutdown::Forced(_)) => ExitCode::from(3),
+        Err(error) => {
+            error!(%error, "Fatal error");
+            ExitCode::FAILURE
+        }
     }
 }
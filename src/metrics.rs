@@ -0,0 +1,434 @@
+use crate::acl::Error as AclError;
+use crate::auth::Error as AuthError;
+use crate::http::Error as HttpError;
+use crate::proxies::BreakerState;
+use crate::socks::{Error as SocksError, Protocol};
+use crate::socks4::Error as Socks4Error;
+use crate::socks5::Error as Socks5Error;
+use anyhow::{Error as AnyError, Result};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::spawn;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info};
+
+const MAX_REQUEST_HEAD: u64 = 8192;
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Pause after a failed accept, which would most likely fail again right away
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProtocolLabels {
+    protocol: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FailureLabels {
+    protocol: &'static str,
+    kind: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DirectionLabels {
+    direction: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProxyLabels {
+    proxy: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ExitLabels {
+    country: String,
+    asn: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BreakerLabels {
+    proxy: String,
+    state: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HandshakeLabels {
+    proxy: String,
+    result: &'static str,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    sessions_accepted: Family<ProtocolLabels, Counter>,
+    sessions_failed: Family<FailureLabels, Counter>,
+    sessions_active: Gauge,
+    bytes_relayed: Family<DirectionLabels, Counter>,
+    chains_established: Family<ExitLabels, Counter>,
+    proxy_handshakes: Family<HandshakeLabels, Counter>,
+    proxy_handshake_duration: Family<ProxyLabels, Histogram>,
+    proxy_up: Family<ProxyLabels, Gauge>,
+    proxy_exit_ok: Family<ProxyLabels, Gauge>,
+    proxy_breaker_state: Family<ProxyLabels, Gauge>,
+    proxy_breaker_transitions: Family<BreakerLabels, Counter>,
+}
+
+fn protocol_name(protocol: Option<Protocol>) -> &'static str {
+    protocol.map(Protocol::name).unwrap_or("unknown")
+}
+
+pub fn error_kind(error: &AnyError) -> &'static str {
+    if let Some(error) = error.downcast_ref::<IoError>() {
+        return match error.kind() {
+            ErrorKind::UnexpectedEof => "eof",
+            ErrorKind::ConnectionRefused => "connection_refused",
+            ErrorKind::ConnectionReset => "connection_reset",
+            ErrorKind::TimedOut => "timeout",
+            _ => "io",
+        };
+    }
+
+    if let Some(error) = error.downcast_ref::<SocksError>() {
+        return match error {
+            SocksError::UnsupportedVersion => "unsupported_version",
+            SocksError::Protocol => "protocol",
+            SocksError::UnsupportedCommand => "unsupported_command",
+            SocksError::ProtocolDisabled(_) => "protocol_disabled",
+        };
+    }
+
+    if let Some(Socks4Error::RequestFailed(_)) = error.downcast_ref::<Socks4Error>() {
+        return "request_failed";
+    }
+
+    if let Some(error) = error.downcast_ref::<Socks5Error>() {
+        return match error {
+            Socks5Error::AuthRejected | Socks5Error::PasswordRejected => "auth_rejected",
+            Socks5Error::RequestFailed(_) => "request_failed",
+            Socks5Error::UnsupportedAuthMethod => "unsupported_auth_method",
+        };
+    }
+
+    if let Some(error) = error.downcast_ref::<HttpError>() {
+        return match error {
+            HttpError::RequestFailed(_) => "request_failed",
+            HttpError::Malformed | HttpError::HeaderTooLong => "protocol",
+            HttpError::UnsupportedCommand => "unsupported_command",
+        };
+    }
+
+    if let Some(AclError::Denied(_)) = error.downcast_ref::<AclError>() {
+        return "denied";
+    }
+
+    if error.downcast_ref::<AuthError>().is_some() {
+        return "unauthorized";
+    }
+
+    "other"
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("rproxychainsd");
+        let sessions_accepted = Family::<ProtocolLabels, Counter>::default();
+        let sessions_failed = Family::<FailureLabels, Counter>::default();
+        let sessions_active = Gauge::default();
+        let bytes_relayed = Family::<DirectionLabels, Counter>::default();
+        let chains_established = Family::<ExitLabels, Counter>::default();
+        let proxy_handshakes = Family::<HandshakeLabels, Counter>::default();
+        let proxy_up = Family::<ProxyLabels, Gauge>::default();
+        let proxy_exit_ok = Family::<ProxyLabels, Gauge>::default();
+        let proxy_breaker_state = Family::<ProxyLabels, Gauge>::default();
+        let proxy_breaker_transitions = Family::<BreakerLabels, Counter>::default();
+
+        let proxy_handshake_duration =
+            Family::<ProxyLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.005, 2.0, 14))
+            });
+
+        registry.register(
+            "sessions_accepted",
+            "Sessions whose SOCKS version could be read",
+            sessions_accepted.clone(),
+        );
+
+        registry.register(
+            "sessions_failed",
+            "Sessions that ended with an error",
+            sessions_failed.clone(),
+        );
+
+        registry.register("sessions_active", "Sessions currently open", sessions_active.clone());
+
+        registry.register(
+            "bytes_relayed",
+            "Bytes relayed between clients and chains",
+            bytes_relayed.clone(),
+        );
+
+        registry.register(
+            "chains_established",
+            "Chains established, by the country and ASN of their exit hop",
+            chains_established.clone(),
+        );
+
+        registry.register(
+            "proxy_handshakes",
+            "Handshakes through each upstream proxy",
+            proxy_handshakes.clone(),
+        );
+
+        registry.register(
+            "proxy_handshake_duration_seconds",
+            "Time between an upstream proxy's reply and the one before it",
+            proxy_handshake_duration.clone(),
+        );
+
+        registry.register(
+            "proxy_up",
+            "Whether an upstream proxy is healthy, by manual mark or else its last handshake",
+            proxy_up.clone(),
+        );
+
+        registry.register(
+            "proxy_exit_ok",
+            "Whether an upstream proxy's last exit check found nothing wrong",
+            proxy_exit_ok.clone(),
+        );
+
+        registry.register(
+            "proxy_breaker_state",
+            "An upstream proxy's circuit breaker, 0 closed, 1 half-open, 2 open",
+            proxy_breaker_state.clone(),
+        );
+
+        registry.register(
+            "proxy_breaker_transitions",
+            "Times an upstream proxy's circuit breaker changed to the given state",
+            proxy_breaker_transitions.clone(),
+        );
+
+        Self {
+            registry,
+            sessions_accepted,
+            sessions_failed,
+            sessions_active,
+            bytes_relayed,
+            chains_established,
+            proxy_handshakes,
+            proxy_handshake_duration,
+            proxy_up,
+            proxy_exit_ok,
+            proxy_breaker_state,
+            proxy_breaker_transitions,
+        }
+    }
+
+    pub fn session_opened(&self) {
+        self.sessions_active.inc();
+    }
+
+    pub fn session_closed(&self) {
+        self.sessions_active.dec();
+    }
+
+    pub fn session_accepted(&self, protocol: Protocol) {
+        let labels = ProtocolLabels {
+            protocol: protocol.name(),
+        };
+
+        self.sessions_accepted.get_or_create(&labels).inc();
+    }
+
+    pub fn session_failed(&self, protocol: Option<Protocol>, error: &AnyError) {
+        let labels = FailureLabels {
+            protocol: protocol_name(protocol),
+            kind: error_kind(error),
+        };
+
+        self.sessions_failed.get_or_create(&labels).inc();
+    }
+
+    pub fn bytes_sent(&self, num: usize) {
+        self.add_bytes("sent", num);
+    }
+
+    pub fn bytes_received(&self, num: usize) {
+        self.add_bytes("received", num);
+    }
+
+    fn add_bytes(&self, direction: &'static str, num: usize) {
+        let labels = DirectionLabels {
+            direction,
+        };
+
+        self.bytes_relayed.get_or_create(&labels).inc_by(num as u64);
+    }
+
+    pub fn chain_established(&self, country: Option<&str>, asn: Option<&str>) {
+        let labels = ExitLabels {
+            country: country.unwrap_or("unknown").to_owned(),
+            asn: asn.unwrap_or("unknown").to_owned(),
+        };
+
+        self.chains_established.get_or_create(&labels).inc();
+    }
+
+    pub fn handshake_succeeded(&self, proxy: &str, duration: Duration) {
+        let labels = ProxyLabels {
+            proxy: proxy.to_owned(),
+        };
+
+        self.proxy_handshake_duration.get_or_create(&labels).observe(duration.as_secs_f64());
+        self.count_handshake(labels.proxy, "success");
+    }
+
+    pub fn handshake_failed(&self, proxy: &str) {
+        self.count_handshake(proxy.to_string(), "failure");
+    }
+
+    pub fn set_proxy_up(&self, proxy: &str, up: bool) {
+        let labels = ProxyLabels {
+            proxy: proxy.to_owned(),
+        };
+
+        self.proxy_up.get_or_create(&labels).set(up.into());
+    }
+
+    pub fn set_exit_ok(&self, proxy: &str, ok: bool) {
+        let labels = ProxyLabels {
+            proxy: proxy.to_owned(),
+        };
+
+        self.proxy_exit_ok.get_or_create(&labels).set(ok.into());
+    }
+
+    pub fn set_breaker_state(&self, proxy: &str, state: BreakerState) {
+        let labels = ProxyLabels {
+            proxy: proxy.to_owned(),
+        };
+
+        self.proxy_breaker_state.get_or_create(&labels).set(state as i64);
+    }
+
+    pub fn breaker_changed(&self, proxy: &str, state: BreakerState) {
+        self.set_breaker_state(proxy, state);
+
+        let labels = BreakerLabels {
+            proxy: proxy.to_string(),
+            state: state.name(),
+        };
+
+        self.proxy_breaker_transitions.get_or_create(&labels).inc();
+    }
+
+    fn count_handshake(&self, proxy: String, result: &'static str) {
+        let labels = HandshakeLabels {
+            proxy,
+            result,
+        };
+
+        self.proxy_handshakes.get_or_create(&labels).inc();
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut buf = String::new();
+        encode(&mut buf, &self.registry)?;
+        Ok(buf)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Returns the request line and consumes the headers, which are irrelevant, but the client expects
+// them to be read before the response. Reads end at the limit, even in the middle of a line that
+// never ends.
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_HEAD);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    loop {
+        let mut line = String::new();
+        let num = reader.read_line(&mut line).await?;
+
+        if num == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    Ok(request_line)
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    // A client that never finishes its request would hold on to the connection for good
+    let head = timeout(REQUEST_HEAD_TIMEOUT, read_request_head(&mut stream));
+    let request_line = head.await.map_err(IoError::from)??;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    debug!(method, path, "Metrics request");
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => (
+            "200 OK",
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            metrics.encode()?,
+        ),
+        ("GET", _) => ("404 Not Found", "text/plain; charset=utf-8", "not found\n".into()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "method not allowed\n".into()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+pub async fn serve(metrics: Arc<Metrics>, host: &str, port: u16) -> Result<()> {
+    let listener = TcpListener::bind((host, port)).await?;
+    info!(host, port, "Metrics endpoint running");
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // Like running out of file descriptors, which shouldn't take the endpoint down for good
+            Err(error) => {
+                error!(%error, "Could not accept metrics connection");
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+
+        spawn(async move {
+            if let Err(error) = handle_request(stream, &metrics).await {
+                debug!(%error, "Metrics request failed");
+            }
+        });
+    }
+}
@@ -0,0 +1,375 @@
+use crate::chain::HandshakeObserver;
+use crate::config::{Breaker, Config, Proxy};
+use crate::exit_check::{ExitReport, Verdict};
+use crate::hop::Hop;
+use crate::metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch::Receiver;
+use tracing::{info, warn};
+
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    #[default]
+    Up,
+    Down,
+}
+
+// Share of the latest handshake in the latency average
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+
+// Closed lets the proxy be picked, open keeps it out for a cooldown, after which half-open lets
+// a single trial connection through
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    #[default]
+    Closed = 0,
+    HalfOpen = 1,
+    Open = 2,
+}
+
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct ProxyStats {
+    health: Health,
+    successes: u64,
+    failures: u64,
+    last_latency_ms: Option<u64>,
+    latency_ewma_ms: Option<f64>,
+    last_outcome: Option<Health>,
+    marked: Option<Health>,
+    exit: Option<ExitReport>,
+    consecutive_failures: u32,
+    breaker: BreakerState,
+    // Times the breaker opened since it was last closed
+    #[serde(skip)]
+    breaker_trips: u32,
+    // End of the cooldown while open, of the trial connection while half-open
+    #[serde(skip)]
+    breaker_until: Option<Instant>,
+    #[serde(skip)]
+    updated_at: Option<SystemTime>,
+}
+
+// What is kept across restarts, with times in seconds since the Unix epoch. Manual marks and
+// counters start over.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedStats {
+    updated_at: u64,
+    last_outcome: Option<Health>,
+    last_latency_ms: Option<u64>,
+    latency_ewma_ms: Option<f64>,
+    consecutive_failures: u32,
+    breaker: BreakerState,
+    breaker_trips: u32,
+    breaker_until: Option<u64>,
+}
+
+// Keyed by the hop identity, e.g. "socks5://127.0.0.1:1080", so any hop type can be tracked
+pub struct Proxies {
+    stats: Mutex<HashMap<String, ProxyStats>>,
+    metrics: Arc<Metrics>,
+    configs: Receiver<Arc<Config>>,
+}
+
+impl BreakerState {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::HalfOpen => "half_open",
+            Self::Open => "open",
+        }
+    }
+}
+
+impl ProxyStats {
+    // A manual mark wins over whatever live traffic last reported
+    fn refresh_health(&mut self) {
+        self.health = self.marked.or(self.last_outcome).unwrap_or(Health::Up);
+    }
+
+    // Open until the cooldown is over, half-open until the trial connection had its chance
+    fn breaker_allows(&self) -> bool {
+        match self.breaker {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen | BreakerState::Open => {
+                self.breaker_until.is_some_and(|until| until <= Instant::now())
+            }
+        }
+    }
+}
+
+impl Proxies {
+    pub fn new(metrics: Arc<Metrics>, configs: Receiver<Arc<Config>>) -> Self {
+        Self {
+            stats: Default::default(),
+            metrics,
+            configs,
+        }
+    }
+
+    fn update<F, R>(&self, proxy: String, f: F) -> R
+    where
+        F: FnOnce(&mut ProxyStats) -> R,
+    {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(proxy.clone()).or_default();
+        entry.updated_at = Some(SystemTime::now());
+        let result = f(entry);
+        entry.refresh_health();
+        self.metrics.set_proxy_up(&proxy, entry.health == Health::Up);
+        result
+    }
+
+    fn set_breaker(&self, proxy: &str, stats: &mut ProxyStats, state: BreakerState) {
+        stats.breaker = state;
+        self.metrics.breaker_changed(proxy, state);
+    }
+
+    // Opens after enough failures in a row, or right away when the trial connection of a
+    // half-open breaker fails, for a cooldown that doubles with every trip
+    fn trip(&self, proxy: &str, stats: &mut ProxyStats, breaker: &Breaker) {
+        let should_open = match stats.breaker {
+            BreakerState::Closed => stats.consecutive_failures >= breaker.failures(),
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+
+        if !should_open {
+            return;
+        }
+
+        stats.breaker_trips += 1;
+        let cooldown = breaker.cooldown(stats.breaker_trips);
+        stats.breaker_until = Some(Instant::now() + cooldown);
+        self.set_breaker(proxy, stats, BreakerState::Open);
+
+        warn!(
+            proxy,
+            failures = stats.consecutive_failures,
+            cooldown_secs = cooldown.as_secs(),
+            "Circuit breaker opened"
+        );
+    }
+
+    // None clears the mark and hands the proxy back to traffic-based health
+    pub fn mark(&self, proxy: &Proxy, health: Option<Health>) {
+        self.update(proxy.to_string(), |stats| stats.marked = health);
+    }
+
+    pub fn set_exit(&self, proxy: &str, report: ExitReport) {
+        self.metrics.set_exit_ok(proxy, report.verdict() == Verdict::Ok);
+        self.update(proxy.to_owned(), |stats| stats.exit = Some(report));
+    }
+
+    // A manual mark decides, otherwise an open breaker takes a proxy out of selection, a single
+    // failed handshake does not
+    pub fn is_usable(&self, proxy: &Proxy) -> bool {
+        let stats = self.stats.lock().unwrap();
+
+        let stats = match stats.get(&proxy.to_string()) {
+            Some(stats) => stats,
+            None => return true,
+        };
+
+        match stats.marked {
+            Some(health) => health == Health::Up,
+            None => self.configs.borrow().breaker().is_none() || stats.breaker_allows(),
+        }
+    }
+
+    // Whether a chain built through the proxy earlier may still be used: it wasn't marked down
+    // and its breaker didn't open since. A half-open breaker's trial may be that very chain.
+    pub fn is_still_usable(&self, proxy: &Proxy) -> bool {
+        let stats = self.stats.lock().unwrap();
+
+        let stats = match stats.get(&proxy.to_string()) {
+            Some(stats) => stats,
+            None => return true,
+        };
+
+        match stats.marked {
+            Some(health) => health == Health::Up,
+            None => {
+                self.configs.borrow().breaker().is_none() || stats.breaker != BreakerState::Open
+            }
+        }
+    }
+
+    // Usable without a trial connection: not marked down, and its breaker, if any, is closed
+    pub fn is_settled(&self, proxy: &Proxy) -> bool {
+        let stats = self.stats.lock().unwrap();
+
+        let stats = match stats.get(&proxy.to_string()) {
+            Some(stats) => stats,
+            None => return true,
+        };
+
+        match stats.marked {
+            Some(health) => health == Health::Up,
+            None => {
+                self.configs.borrow().breaker().is_none() || stats.breaker == BreakerState::Closed
+            }
+        }
+    }
+
+    // Called with a freshly picked chain. Breakers whose cooldown is over turn half-open and the
+    // chain becomes their trial connection; false if another chain got there first.
+    pub fn claim_trials(&self, chain: &[Proxy]) -> bool {
+        let config = self.configs.borrow().clone();
+
+        let breaker = match config.breaker() {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+
+        let mut stats = self.stats.lock().unwrap();
+        let mut trials = Vec::new();
+
+        for proxy in chain {
+            let proxy = proxy.to_string();
+
+            match stats.get(&proxy) {
+                Some(stats) if stats.marked.is_none() && stats.breaker != BreakerState::Closed => {
+                    if !stats.breaker_allows() {
+                        return false;
+                    }
+
+                    trials.push(proxy);
+                }
+                _ => {}
+            }
+        }
+
+        for proxy in trials {
+            let stats = stats.get_mut(&proxy).unwrap();
+            let cooldown = breaker.cooldown(stats.breaker_trips);
+            stats.breaker_until = Some(Instant::now() + cooldown);
+
+            if stats.breaker == BreakerState::Open {
+                self.set_breaker(&proxy, stats, BreakerState::HalfOpen);
+                info!(proxy, "Circuit breaker half-open, trying one connection");
+            }
+        }
+
+        true
+    }
+
+    pub fn save(&self) -> BTreeMap<String, SavedStats> {
+        let stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        let unix_now = unix_secs(SystemTime::now());
+
+        let saved = stats.iter().map(|(proxy, stats)| {
+            let breaker_until = stats
+                .breaker_until
+                .map(|until| unix_now + until.saturating_duration_since(now).as_secs());
+
+            let saved = SavedStats {
+                updated_at: stats.updated_at.map(unix_secs).unwrap_or(unix_now),
+                last_outcome: stats.last_outcome,
+                last_latency_ms: stats.last_latency_ms,
+                latency_ewma_ms: stats.latency_ewma_ms,
+                consecutive_failures: stats.consecutive_failures,
+                breaker: stats.breaker,
+                breaker_trips: stats.breaker_trips,
+                breaker_until,
+            };
+
+            (proxy.clone(), saved)
+        });
+
+        saved.collect()
+    }
+
+    // Entries last updated more than max_age ago are left out, returns how many were taken
+    pub fn restore(&self, saved: BTreeMap<String, SavedStats>, max_age: Duration) -> usize {
+        let now = Instant::now();
+        let unix_now = unix_secs(SystemTime::now());
+        let mut restored = 0;
+
+        for (proxy, saved) in saved {
+            if unix_now.saturating_sub(saved.updated_at) > max_age.as_secs() {
+                continue;
+            }
+
+            self.update(proxy.clone(), |stats| {
+                stats.updated_at = Some(UNIX_EPOCH + Duration::from_secs(saved.updated_at));
+                stats.last_outcome = saved.last_outcome;
+                stats.last_latency_ms = saved.last_latency_ms;
+                stats.latency_ewma_ms = saved.latency_ewma_ms;
+                stats.consecutive_failures = saved.consecutive_failures;
+                stats.breaker = saved.breaker;
+                stats.breaker_trips = saved.breaker_trips;
+                stats.breaker_until = saved
+                    .breaker_until
+                    .map(|until| now + Duration::from_secs(until.saturating_sub(unix_now)));
+            });
+
+            self.metrics.set_breaker_state(&proxy, saved.breaker);
+            restored += 1;
+        }
+
+        restored
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, ProxyStats)> {
+        let stats = self.stats.lock().unwrap();
+        let mut snapshot: Vec<_> = stats.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        snapshot.sort_by(|(a, _), (b, _)| a.cmp(b));
+        snapshot
+    }
+}
+
+impl HandshakeObserver for Proxies {
+    fn handshake_succeeded(&self, hop: &dyn Hop, duration: Duration) {
+        let proxy = hop.to_string();
+        self.metrics.handshake_succeeded(&proxy, duration);
+
+        self.update(proxy.clone(), |stats| {
+            stats.successes += 1;
+            stats.consecutive_failures = 0;
+            stats.last_latency_ms = Some(duration.as_millis() as u64);
+
+            let latency = duration.as_secs_f64() * 1000.0;
+            stats.latency_ewma_ms = Some(match stats.latency_ewma_ms {
+                Some(average) => average + LATENCY_EWMA_WEIGHT * (latency - average),
+                None => latency,
+            });
+            stats.last_outcome = Some(Health::Up);
+
+            if stats.breaker != BreakerState::Closed {
+                stats.breaker_trips = 0;
+                stats.breaker_until = None;
+                self.set_breaker(&proxy, stats, BreakerState::Closed);
+                info!(proxy, "Circuit breaker closed");
+            }
+        });
+    }
+
+    fn handshake_failed(&self, hop: &dyn Hop) {
+        let proxy = hop.to_string();
+        self.metrics.handshake_failed(&proxy);
+
+        let config = self.configs.borrow().clone();
+
+        self.update(proxy.clone(), |stats| {
+            stats.failures += 1;
+            stats.consecutive_failures += 1;
+            stats.last_outcome = Some(Health::Down);
+
+            if let Some(breaker) = config.breaker() {
+                self.trip(&proxy, stats, breaker);
+            }
+        });
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,278 @@
+use crate::config::{Chain, Config, Proxy};
+use crate::hop::Endpoint;
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use std::sync::Arc;
+use thiserror::Error;
+use toml::Value;
+
+pub const USAGE: &str = "\
+usage: rproxychainsd import FILE
+       rproxychainsd export
+
+import prints a proxychains.conf as an rproxychainsd config, export prints the top level
+[[chains]] of the config file ($CONFIG or config.toml) as a proxychains.conf.";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid arguments\n\n{USAGE}")]
+    Usage,
+    #[error("invalid proxy at line {0}, expected \"type host port [user [pass]]\"")]
+    InvalidProxy(usize),
+    #[error("unsupported proxy type {0} at line {1}")]
+    UnsupportedType(String, usize),
+    #[error("invalid chain_len at line {0}")]
+    InvalidChainLen(usize),
+    #[error("no proxies in [ProxyList]")]
+    NoProxies,
+    #[error("proxy {0} can't be written to proxychains.conf")]
+    UnsupportedProxy(String),
+    #[error("only chains of a single proxy each, or chains that all have the same proxies, can be exported")]
+    UnsupportedChains,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mode {
+    Strict,
+    Dynamic,
+    Random,
+    RoundRobin,
+}
+
+struct ListEntry {
+    kind: String,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+// Options that have no counterpart here are kept as comments in the output, so nothing is
+// dropped silently
+pub fn import(conf: &str) -> Result<String> {
+    // What proxychains-ng falls back to without a chain mode
+    let mut mode = Mode::Dynamic;
+    let mut chain_len = 1;
+    let mut proxy_dns = false;
+    let mut ignored = Vec::new();
+    let mut proxies = Vec::new();
+    let mut in_list = false;
+
+    for (index, line) in conf.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+
+        if line.is_empty() {
+            continue;
+        } else if line == "[ProxyList]" {
+            in_list = true;
+        } else if in_list {
+            proxies.push(parse_entry(line, index + 1)?);
+        } else {
+            let (key, value) = match line.split_once(|c: char| c == '=' || c.is_whitespace()) {
+                Some((key, value)) => (key, value.trim().trim_start_matches('=').trim()),
+                None => (line, ""),
+            };
+
+            match key {
+                "strict_chain" => mode = Mode::Strict,
+                "dynamic_chain" => mode = Mode::Dynamic,
+                "random_chain" => mode = Mode::Random,
+                "round_robin_chain" => mode = Mode::RoundRobin,
+                "chain_len" => {
+                    chain_len = value
+                        .parse()
+                        .ok()
+                        .filter(|len| *len > 0)
+                        .ok_or(Error::InvalidChainLen(index + 1))?
+                }
+                "proxy_dns" => proxy_dns = true,
+                _ => ignored.push(line),
+            }
+        }
+    }
+
+    if proxies.is_empty() {
+        Err(Error::NoProxies)?;
+    }
+
+    let mut output = String::from("# Imported from proxychains.conf\n");
+
+    match mode {
+        Mode::Strict => {}
+        Mode::Dynamic => output.push_str(
+            "# dynamic_chain: proxies that are down are left out of the chain there, here every\n\
+             # chain needs one that works\n",
+        ),
+        Mode::Random | Mode::RoundRobin => writeln!(
+            output,
+            "# {}_chain with chain_len = {}: every chain picks from all proxies on its own, so the\n\
+             # same proxy can come up twice",
+            if mode == Mode::Random { "random" } else { "round_robin" },
+            chain_len,
+        )?,
+    }
+
+    if proxy_dns {
+        output.push_str(
+            "# proxy_dns: destinations are taken as addresses only, clients resolve names themselves\n",
+        );
+    }
+
+    if proxies.iter().any(|entry| entry.kind == "socks4" && entry.credentials.is_some()) {
+        output.push_str("# socks4 user ids are not supported and were left out\n");
+    }
+
+    for line in ignored {
+        writeln!(output, "# Not imported: {}", line)?;
+    }
+
+    output.push_str("\n[server]\nhost = \"127.0.0.1\"\nport = 1080\n");
+
+    match mode {
+        Mode::Strict | Mode::Dynamic => {
+            for entry in &proxies {
+                writeln!(output, "\n[[chains]]\nentries = [{}]", inline_entry(entry))?;
+            }
+        }
+        Mode::Random | Mode::RoundRobin => {
+            let mut names = Vec::new();
+
+            for (index, entry) in proxies.iter().enumerate() {
+                let name = format!("proxy{}", index + 1);
+                writeln!(output, "\n[[proxies]]\nname = {}", quote(&name))?;
+                writeln!(output, "type = {}", quote(&entry.kind))?;
+                writeln!(output, "host = {}\nport = {}", quote(&entry.host), entry.port)?;
+
+                if let Some((username, password)) = credentials(entry) {
+                    writeln!(
+                        output,
+                        "username = {}\npassword = {}",
+                        quote(username),
+                        quote(password)
+                    )?;
+                }
+
+                names.push(quote(&name));
+            }
+
+            writeln!(output, "\n[pools]\nproxychains = [{}]", names.join(", "))?;
+
+            for _ in 0..chain_len {
+                output.push_str("\n[[chains]]\npool = \"proxychains\"\n");
+            }
+        }
+    }
+
+    // Whatever went into the file has to load as a config
+    output.parse::<Config>().map_err(|error| anyhow!("imported config is invalid: {}", error))?;
+
+    Ok(output)
+}
+
+fn parse_entry(line: &str, number: usize) -> Result<ListEntry> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+
+    let (kind, host, port, credentials) = match fields.as_slice() {
+        [kind, host, port] => (kind, host, port, None),
+        [kind, host, port, username] => {
+            (kind, host, port, Some((username.to_string(), String::new())))
+        }
+        [kind, host, port, username, password] => {
+            (kind, host, port, Some((username.to_string(), password.to_string())))
+        }
+        _ => Err(Error::InvalidProxy(number))?,
+    };
+
+    if !["socks4", "socks5", "http"].contains(kind) {
+        Err(Error::UnsupportedType(kind.to_string(), number))?;
+    }
+
+    Ok(ListEntry {
+        kind: kind.to_string(),
+        host: host.to_string(),
+        port: port.parse().map_err(|_| Error::InvalidProxy(number))?,
+        credentials,
+    })
+}
+
+fn credentials(entry: &ListEntry) -> Option<(&str, &str)> {
+    match &entry.credentials {
+        Some((username, password)) if entry.kind != "socks4" => Some((username, password)),
+        _ => None,
+    }
+}
+
+fn inline_entry(entry: &ListEntry) -> String {
+    match credentials(entry) {
+        Some((username, password)) => format!(
+            "{{ type = {}, host = {}, port = {}, username = {}, password = {} }}",
+            quote(&entry.kind),
+            quote(&entry.host),
+            entry.port,
+            quote(username),
+            quote(password),
+        ),
+        None => format!("[{}, {}, {}]", quote(&entry.kind), quote(&entry.host), entry.port),
+    }
+}
+
+fn quote(value: &str) -> String {
+    Value::String(value.to_owned()).to_string()
+}
+
+// proxychains only knows one list of proxies, which is either used in order or picked from at
+// random, so those are the two shapes of chains that can be written out. Chains reading a list
+// file are exported with the proxies the file holds right now.
+pub fn export(config: &Config) -> Result<String> {
+    let chains: Vec<Arc<[Proxy]>> = config.chains().iter().map(Chain::entries).collect();
+
+    let (mode, proxies) = if chains.iter().all(|entries| entries.len() == 1) {
+        ("strict_chain\n".to_owned(), chains.iter().map(|entries| entries[0].clone()).collect())
+    } else if chains.iter().all(|entries| entries == &chains[0]) {
+        (format!("random_chain\nchain_len = {}\n", chains.len()), chains[0].to_vec())
+    } else {
+        Err(Error::UnsupportedChains)?
+    };
+
+    if proxies.is_empty() {
+        Err(Error::NoProxies)?;
+    }
+
+    let mut output = String::from("# Exported from rproxychainsd\n");
+    output.push_str(&mode);
+    output.push_str(
+        "# Without proxy_dns names are looked up locally, as clients of rproxychainsd do\n",
+    );
+    output.push_str("\n[ProxyList]\n");
+
+    for proxy in &proxies {
+        let (host, port) = match proxy.endpoint() {
+            Endpoint::Tcp(addr) if proxy.tls().is_none() => (addr.ip().to_string(), addr.port()),
+            Endpoint::Host(host, port) if proxy.tls().is_none() => (host.clone(), *port),
+            _ => Err(Error::UnsupportedProxy(proxy.to_string()))?,
+        };
+
+        write!(output, "{} {} {}", proxy.kind().name(), host, port)?;
+
+        if let Some(credentials) = proxy.credentials() {
+            write!(output, " {} {}", credentials.username(), credentials.password())?;
+        }
+
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+pub async fn run(config_file: &str, args: &[String]) -> Result<()> {
+    let output = match args {
+        [command, file] if command == "import" => {
+            let conf = tokio::fs::read_to_string(file).await?;
+            import(&conf).map_err(|error| anyhow!("{}: {}", file, error))?
+        }
+        [command] if command == "export" => export(&Config::read_file(config_file).await?)?,
+        _ => Err(Error::Usage)?,
+    };
+
+    print!("{}", output);
+    Ok(())
+}
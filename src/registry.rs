@@ -0,0 +1,122 @@
+use crate::config::Proxy;
+use crate::listener::ClientAddr;
+use crate::socks::Protocol;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::task::AbortHandle;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SessionInfo {
+    id: u64,
+    client: ClientAddr,
+    identity: Option<String>,
+    protocol: Option<Protocol>,
+    destination: Option<SocketAddr>,
+    chain: Vec<String>,
+    bound: Option<SocketAddr>,
+    age_secs: u64,
+}
+
+struct Entry {
+    client: ClientAddr,
+    identity: Option<String>,
+    started: Instant,
+    protocol: Option<Protocol>,
+    destination: Option<SocketAddr>,
+    chain: Vec<Proxy>,
+    bound: Option<SocketAddr>,
+    abort: Option<AbortHandle>,
+}
+
+#[derive(Default)]
+pub struct SessionRegistry {
+    entries: Mutex<BTreeMap<u64, Entry>>,
+}
+
+impl SessionRegistry {
+    pub fn insert(&self, id: u64, client: ClientAddr) {
+        let entry = Entry {
+            client,
+            identity: None,
+            started: Instant::now(),
+            protocol: None,
+            destination: None,
+            chain: Vec::new(),
+            bound: None,
+            abort: None,
+        };
+
+        self.entries.lock().unwrap().insert(id, entry);
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.entries.lock().unwrap().remove(&id);
+    }
+
+    pub fn set_abort(&self, id: u64, abort: AbortHandle) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.abort = Some(abort);
+        }
+    }
+
+    pub fn set_identity(&self, id: u64, identity: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.identity = Some(identity.to_owned());
+        }
+    }
+
+    pub fn set_protocol(&self, id: u64, protocol: Protocol) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.protocol = Some(protocol);
+        }
+    }
+
+    pub fn set_destination(&self, id: u64, destination: SocketAddr) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.destination = Some(destination);
+        }
+    }
+
+    pub fn set_chain(&self, id: u64, chain: &[Proxy]) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.chain = chain.to_vec();
+        }
+    }
+
+    pub fn set_bound(&self, id: u64, bound: SocketAddr) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            entry.bound = Some(bound);
+        }
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().get(&id).and_then(|entry| entry.abort.as_ref()) {
+            Some(abort) => {
+                abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .iter()
+            .map(|(&id, entry)| SessionInfo {
+                id,
+                client: entry.client.clone(),
+                identity: entry.identity.clone(),
+                protocol: entry.protocol,
+                destination: entry.destination,
+                chain: entry.chain.iter().map(|proxy| proxy.to_string()).collect(),
+                bound: entry.bound,
+                age_secs: entry.started.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,54 @@
+use crate::config::Config;
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch::{channel, Receiver, Sender};
+use tracing::{error, info};
+
+#[derive(Clone)]
+pub struct Reloader {
+    file_name: Arc<str>,
+    sender: Arc<Sender<Arc<Config>>>,
+}
+
+impl Reloader {
+    pub fn new(file_name: &str, config: Config) -> Self {
+        let (sender, _) = channel(config.into());
+
+        Self {
+            file_name: file_name.into(),
+            sender: sender.into(),
+        }
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn subscribe(&self) -> Receiver<Arc<Config>> {
+        self.sender.subscribe()
+    }
+
+    // Only replaces the active config if the new one parsed and validated; sessions that are
+    // already running keep the Arc they were started with.
+    pub async fn reload(&self) -> Result<()> {
+        let config = Config::read_file(&self.file_name).await?;
+        self.sender.send_replace(config.into());
+        Ok(())
+    }
+}
+
+pub async fn reload_on_hangup(reloader: Reloader) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        info!(file = reloader.file_name(), "Received SIGHUP, reloading config");
+
+        match reloader.reload().await {
+            Ok(()) => info!("Config reloaded"),
+            Err(error) => error!(%error, "Config reload failed, keeping old config"),
+        }
+    }
+
+    Ok(())
+}
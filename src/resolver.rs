@@ -0,0 +1,73 @@
+use crate::config::Dns;
+use anyhow::Result;
+use hickory_resolver::config::LookupIpStrategy;
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use std::net::{IpAddr, SocketAddrV4};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::debug;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0} has no IPv4 address")]
+    NoAddresses(String),
+}
+
+// Looks up the hostnames of first hops. Answers are cached for as long as their TTL says, kept
+// within the [dns] bounds, and looked up again once they expire, so proxies with rotating
+// addresses are followed. The system config is only read for the first lookup, chains starting at
+// an address never need it.
+pub struct Resolver {
+    min_ttl: Option<Duration>,
+    max_ttl: Option<Duration>,
+    resolver: OnceLock<TokioAsyncResolver>,
+}
+
+impl Resolver {
+    pub fn new(dns: &Dns) -> Self {
+        Self {
+            min_ttl: dns.min_ttl(),
+            max_ttl: dns.max_ttl(),
+            resolver: OnceLock::new(),
+        }
+    }
+
+    fn resolver(&self) -> &TokioAsyncResolver {
+        self.resolver.get_or_init(|| {
+            // Without a resolv.conf, e.g. in a container, the library defaults are used
+            let (config, mut options) = read_system_conf().unwrap_or_default();
+            options.ip_strategy = LookupIpStrategy::Ipv4Only;
+            options.positive_min_ttl = self.min_ttl;
+            options.positive_max_ttl = self.max_ttl;
+            TokioAsyncResolver::tokio(config, options)
+        })
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddrV4>> {
+        let lookup = self.resolver().lookup_ip(host).await?;
+
+        let addrs: Vec<_> = lookup
+            .iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) => Some(SocketAddrV4::new(ip, port)),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+
+        if addrs.is_empty() {
+            Err(Error::NoAddresses(host.to_owned()))?;
+        }
+
+        let ttl = lookup.valid_until().saturating_duration_since(Instant::now());
+        debug!(host, ?addrs, ttl = ttl.as_secs(), "Resolved proxy");
+        Ok(addrs)
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(&Dns::default())
+    }
+}
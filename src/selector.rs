@@ -0,0 +1,246 @@
+use crate::config::{Chain, Chains, Config, Proxy};
+use crate::proxies::Proxies;
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no usable proxy in chain {0} (marked down, breaker open or weight 0)")]
+    NoUsableProxy(usize),
+    #[error("unknown profile {0}")]
+    UnknownProfile(String),
+    #[error("no chain of usable proxies has hops that differ in {0}")]
+    NoDistinctChain(String),
+}
+
+struct Current {
+    config: Weak<Config>,
+    chain: Vec<Proxy>,
+    picked: Instant,
+}
+
+pub struct Selector {
+    proxies: Arc<Proxies>,
+    current: Mutex<HashMap<String, Current>>,
+}
+
+impl Selector {
+    pub fn new(proxies: Arc<Proxies>) -> Self {
+        Self {
+            proxies,
+            current: Default::default(),
+        }
+    }
+
+    // Picks again if another session took the trial connection of a half-open proxy in the
+    // meantime, which then no longer counts as usable. Warm chains are only finished by a session
+    // much later, so they can't carry a trial and leave out proxies that would need one.
+    fn pick(&self, chains: &[Chain], distinct: &[String], warm: bool) -> Result<Vec<Proxy>> {
+        if warm {
+            return self.pick_once(chains, distinct, |proxy| self.proxies.is_settled(proxy));
+        }
+
+        loop {
+            let chain = self.pick_once(chains, distinct, |proxy| self.proxies.is_usable(proxy))?;
+
+            if self.proxies.claim_trials(&chain) {
+                return Ok(chain);
+            }
+        }
+    }
+
+    fn pick_once(
+        &self,
+        chains: &[Chain],
+        distinct: &[String],
+        is_usable: impl Fn(&Proxy) -> bool,
+    ) -> Result<Vec<Proxy>> {
+        let entries: Vec<_> = chains.iter().map(Chain::entries).collect();
+        let mut candidates = Vec::new();
+
+        for (index, entries) in entries.iter().enumerate() {
+            // Proxies with a weight of 0 are never picked
+            let usable: Vec<_> =
+                entries.iter().filter(|proxy| proxy.weight() > 0 && is_usable(proxy)).collect();
+
+            if usable.is_empty() {
+                Err(Error::NoUsableProxy(index))?;
+            }
+
+            candidates.push(usable);
+        }
+
+        let chain = choose(&candidates, distinct)
+            .ok_or_else(|| Error::NoDistinctChain(distinct.join(", ")))?;
+
+        Ok(chain.into_iter().cloned().collect())
+    }
+
+    // With chain_lifetime set, sessions of a profile share one chain until it expires, gets
+    // rotated, loses a proxy to a down mark or the config is reloaded. Otherwise every session
+    // rolls its own.
+    pub fn make_chain(&self, config: &Arc<Config>, profile: &str) -> Result<Vec<Proxy>> {
+        self.make(config, profile, false)
+    }
+
+    // Same for the warm pool, only through proxies that need no trial connection
+    pub fn make_warm_chain(&self, config: &Arc<Config>, profile: &str) -> Result<Vec<Proxy>> {
+        self.make(config, profile, true)
+    }
+
+    fn make(&self, config: &Arc<Config>, profile: &str, warm: bool) -> Result<Vec<Proxy>> {
+        let chains =
+            config.profile(profile).ok_or_else(|| Error::UnknownProfile(profile.to_owned()))?;
+
+        let lifetime = match config.server().chain_lifetime() {
+            Some(lifetime) => lifetime,
+            None => return self.pick(chains, chains.distinct(), warm),
+        };
+
+        let mut current = self.current.lock().unwrap();
+        let is_usable = |proxy| match warm {
+            true => self.proxies.is_settled(proxy),
+            false => self.proxies.is_usable(proxy),
+        };
+
+        if let Some(current) = current.get(profile) {
+            if current.config.ptr_eq(&Arc::downgrade(config))
+                && current.picked.elapsed() < lifetime
+                && current.chain.iter().all(is_usable)
+            {
+                return Ok(current.chain.clone());
+            }
+        }
+
+        let chain = self.pick(chains, chains.distinct(), warm)?;
+
+        let picked = Current {
+            config: Arc::downgrade(config),
+            chain: chain.clone(),
+            picked: Instant::now(),
+        };
+
+        current.insert(profile.to_owned(), picked);
+
+        Ok(chain)
+    }
+
+    // A chain that ends in the given proxy of chain index, for checking it as the exit
+    pub fn make_chain_to(
+        &self,
+        chains: &Chains,
+        index: usize,
+        proxy: &Proxy,
+    ) -> Result<Vec<Proxy>> {
+        let mut chain = self.pick(&chains[..index], chains.distinct(), false)?;
+        chain.push(proxy.clone());
+        Ok(chain)
+    }
+
+    pub fn rotate(&self) {
+        self.current.lock().unwrap().clear();
+    }
+}
+
+// Proxies of one chain with the same values for every distinct tag, which are interchangeable as
+// far as the search is concerned
+struct Group<'a> {
+    key: Vec<Option<&'a str>>,
+    proxies: Vec<&'a Proxy>,
+}
+
+impl<'a> Group<'a> {
+    fn of(usable: &[&'a Proxy], distinct: &[String]) -> Vec<Self> {
+        let mut groups: HashMap<_, Vec<_>> = HashMap::new();
+
+        for proxy in usable {
+            let key = distinct.iter().map(|key| proxy.tag(key)).collect();
+            groups.entry(key).or_default().push(*proxy);
+        }
+
+        groups
+            .into_iter()
+            .map(|(key, proxies)| Self {
+                key,
+                proxies,
+            })
+            .collect()
+    }
+
+    // As likely to come first as picking any of its proxies by weight would be
+    fn weight(&self) -> u32 {
+        self.proxies.iter().map(|proxy| proxy.weight()).sum()
+    }
+
+    fn pick(&self) -> &'a Proxy {
+        self.proxies.choose_weighted(&mut thread_rng(), |proxy| proxy.weight()).unwrap()
+    }
+
+    // Proxies without one of the tags don't clash over it
+    fn clashes(&self, other: &Self) -> bool {
+        self.key.iter().zip(&other.key).any(|(first, second)| first.is_some() && first == second)
+    }
+}
+
+fn choose<'a>(candidates: &[Vec<&'a Proxy>], distinct: &[String]) -> Option<Vec<&'a Proxy>> {
+    let groups: Vec<_> = candidates.iter().map(|usable| Group::of(usable, distinct)).collect();
+    let mut chain = Vec::new();
+
+    if !choose_groups(&groups, &mut chain, &mut HashSet::new()) {
+        return None;
+    }
+
+    Some(chain.iter().map(|group| group.pick()).collect())
+}
+
+// Goes through the groups of each chain in weighted random order and backs up as soon as a later
+// chain is left without a group that fits the ones picked so far. Whether the rest can be filled
+// in only depends on which keys are taken, so dead ends are remembered by those.
+fn choose_groups<'g, 'a>(
+    groups: &'g [Vec<Group<'a>>],
+    chain: &mut Vec<&'g Group<'a>>,
+    dead_ends: &mut HashSet<Vec<&'g [Option<&'a str>]>>,
+) -> bool {
+    let level = match groups.get(chain.len()) {
+        Some(level) => level,
+        None => return true,
+    };
+
+    let fits = |chain: &[&Group], group: &Group| !chain.iter().any(|hop| hop.clashes(group));
+
+    let order = level
+        .choose_multiple_weighted(&mut thread_rng(), level.len(), |group| group.weight())
+        .expect("weights are positive");
+
+    for group in order {
+        if !fits(chain, group) {
+            continue;
+        }
+
+        chain.push(group);
+
+        let mut taken: Vec<_> = chain.iter().map(|hop| hop.key.as_slice()).collect();
+        taken.sort();
+
+        let open = groups[chain.len()..]
+            .iter()
+            .all(|later| later.iter().any(|candidate| fits(chain, candidate)));
+
+        if open && !dead_ends.contains(&taken) {
+            if choose_groups(groups, chain, dead_ends) {
+                return true;
+            }
+
+            dead_ends.insert(taken);
+        }
+
+        chain.pop();
+    }
+
+    false
+}
@@ -1,33 +1,190 @@
-use crate::config::Config;
+use crate::access_log::AccessLog;
+use crate::config::{Config, Listener};
+use crate::context::Context;
+use crate::hop::BoxedStream;
+use crate::listener::{ClientAddr, ListenAddr, Socket};
 use crate::session::Session;
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use futures::future::select_all;
+use std::future::{pending, Future};
+use std::io::Result as IoResult;
+use std::time::Duration;
+use tokio::pin;
+use tokio::select;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+use tracing::{error, info, warn};
+
+// Pause after a failed accept, which would most likely fail again right away
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 pub struct Server {
-    config: Arc<Config>,
+    context: Context,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Shutdown {
+    Drained,
+    Forced(usize),
+}
+
+// A listening socket along with the address it was configured with
+struct Bound {
+    address: ListenAddr,
+    socket: Socket,
+}
+
+impl Bound {
+    fn is(&self, listener: &Listener) -> bool {
+        &self.address == listener.address()
+    }
+}
+
+async fn bind(listener: &Listener) -> Result<Bound> {
+    info!(address = %listener.address(), "Trying to bind");
+
+    Ok(Bound {
+        address: listener.address().clone(),
+        socket: Socket::bind(listener.address(), listener.mode()).await?,
+    })
+}
+
+// Sockets of listeners that are still configured are kept, so connections waiting on them aren't
+// lost. A new listener that can't be bound is left out, the others keep working.
+async fn rebind(mut old: Vec<Bound>, config: &Config) -> Vec<Bound> {
+    let mut bound = Vec::new();
+
+    for listener in config.listeners() {
+        if let Some(index) = old.iter().position(|socket| socket.is(listener)) {
+            let socket = old.swap_remove(index);
+
+            if let Err(error) = socket.socket.set_mode(listener.mode()).await {
+                error!(%error, address = %listener.address(), "Could not set permissions");
+            }
+
+            bound.push(socket);
+            continue;
+        }
+
+        match bind(listener).await {
+            Ok(socket) => bound.push(socket),
+            Err(error) => error!(%error, address = %listener.address(), "Could not bind"),
+        }
+    }
+
+    for socket in old {
+        info!(address = %socket.address, "Listener closed");
+    }
+
+    bound
+}
+
+// The next connection on any of the sockets, along with the index of the one it came in on
+async fn accept(bound: &[Bound]) -> (usize, IoResult<(BoxedStream, ClientAddr)>) {
+    if bound.is_empty() {
+        return pending().await;
+    }
+
+    let (accepted, index, _) =
+        select_all(bound.iter().map(|socket| Box::pin(socket.socket.accept()))).await;
+
+    (index, accepted)
+}
+
+async fn drain(sessions: &mut JoinSet<()>, config: &Config) -> Shutdown {
+    let drain_timeout = config.server().drain_timeout();
+
+    info!(
+        sessions = sessions.len(),
+        timeout = drain_timeout.as_secs(),
+        "Shutting down, draining sessions"
+    );
+
+    let drained = timeout(drain_timeout, async { while sessions.join_next().await.is_some() {} });
+
+    if drained.await.is_ok() {
+        info!("All sessions finished");
+        return Shutdown::Drained;
+    }
+
+    let remaining = sessions.len();
+    warn!(sessions = remaining, "Drain timeout reached, closing remaining sessions");
+    sessions.shutdown().await;
+    Shutdown::Forced(remaining)
 }
 
 impl Server {
-    pub fn new(config: Arc<Config>) -> Self {
+    pub fn new(context: Context) -> Self {
         Self {
-            config,
+            context,
         }
     }
 
-    pub async fn run(&self) -> Result<()> {
-        let server_config = self.config.server();
-        let host = server_config.host().clone();
-        let port = server_config.port();
-        println!("[info] Trying to bind to {}:{}", host, port);
-        let server = TcpListener::bind((host, port)).await?;
-        println!("[info] Server running");
+    pub async fn run<F>(&self, shutdown: F) -> Result<Shutdown>
+    where
+        F: Future<Output = ()>,
+    {
+        let mut config = self.context.reloader().subscribe();
+        let mut current = config.borrow_and_update().clone();
+        let mut bound = Vec::new();
+
+        // Unlike on reload, every listener has to come up at startup
+        for listener in current.listeners() {
+            bound.push(bind(listener).await?);
+        }
+
+        let mut sessions = JoinSet::new();
+        let (access_log, access_log_writer) = AccessLog::spawn(self.context.reloader().subscribe());
+        info!("Server running");
+        pin!(shutdown);
 
         loop {
-            let (client_stream, client_addr) = server.accept().await?;
-            println!("[info] [{}] Accepted", client_addr);
-            let session = Session::new(self.config.clone(), client_addr);
-            session.spawn_task(client_stream);
+            select! {
+                (index, accepted) = accept(&bound) => {
+                    let (client_stream, client_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        // Errors like running out of file descriptors pass, and live sessions
+                        // shouldn't go with them
+                        Err(error) => {
+                            error!(%error, "Could not accept connection");
+                            sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let socket = &bound[index];
+
+                    // A socket always has its listener in the config it was last rebound for
+                    let listener = match current.listeners().iter().position(|l| socket.is(l)) {
+                        Some(listener) => listener,
+                        None => continue,
+                    };
+
+                    let session = Session::new(
+                        self.context.clone(),
+                        current.clone(),
+                        access_log.clone(),
+                        listener,
+                        client_addr,
+                    );
+                    session.spawn_task(client_stream, &mut sessions);
+                }
+
+                Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+
+                changed = config.changed() => {
+                    changed?;
+                    current = config.borrow_and_update().clone();
+                    bound = rebind(bound, &current).await;
+                }
+
+                () = &mut shutdown => break,
+            }
         }
+
+        drop(bound);
+        let shutdown = drain(&mut sessions, &current).await;
+        drop(access_log);
+        access_log_writer.await?;
+        Ok(shutdown)
     }
 }
@@ -1,184 +1,307 @@
-use crate::config::{Config, Proxy};
-use crate::socks::{read_version, SocksVersion};
+use crate::access_log::{AccessLog, Record};
+use crate::acl::Error as AclError;
+use crate::auth::{Credentials, Error as AuthError};
+use crate::chain::{ChainConnector, Tunnel};
+use crate::codec::{read_frame_after, write_frame};
+use crate::config::{Client, Config, Listener, Proxy};
+use crate::context::Context;
+use crate::hop::{BoxedStream, Command};
+use crate::http::{HttpConnectReply, HttpConnectRequest};
+use crate::listener::ClientAddr;
+use crate::socks::{read_protocol, Error as SocksError, Protocol};
 use crate::socks4::{Socks4Command, Socks4Reply};
 use crate::socks5::{
-    read_socks5_auth_reply, read_socks5_auth_request, write_socks5_auth, write_socks5_auth_reply,
-    Socks5Command, Socks5Reply,
+    read_socks5_auth_request, write_socks5_auth_reply, Error as Socks5Error, Socks5Command,
+    Socks5PasswordReply, Socks5PasswordRequest, Socks5Reply, NO_ACCEPTABLE_METHOD, NO_AUTH,
+    PASSWORD_AUTH,
 };
-use anyhow::Result;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
-use std::net::{Ipv4Addr, SocketAddr};
+use anyhow::{Error as AnyError, Result};
+use serde::Serialize;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt};
 use tokio::select;
-use tokio::task::spawn;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Termination {
+    ClientClosed,
+    UpstreamClosed,
+    Error,
+    Aborted,
+}
 
 pub struct Session {
+    id: u64,
+    context: Context,
     config: Arc<Config>,
-    ip: SocketAddr,
+    access_log: AccessLog,
+    listener: usize,
+    client: ClientAddr,
+    identity: Option<String>,
+    started: Instant,
+    protocol: Option<Protocol>,
+    destination: Option<SocketAddr>,
+    chain: Vec<Proxy>,
+    warm: Option<Tunnel>,
+    bound: Option<SocketAddr>,
+    handshake: Option<Duration>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    termination: Termination,
+    error: Option<String>,
 }
 
-async fn connect_to_proxy(proxy: &Proxy) -> Result<TcpStream> {
-    match proxy {
-        &Proxy::Socks4(ip, port) => Ok(TcpStream::connect((ip, port)).await?),
-        &Proxy::Socks5(ip, port) => Ok(TcpStream::connect((ip, port)).await?),
+impl Session {
+    pub fn new(
+        context: Context,
+        config: Arc<Config>,
+        access_log: AccessLog,
+        listener: usize,
+        client: ClientAddr,
+    ) -> Self {
+        let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        context.metrics().session_opened();
+        context.sessions().insert(id, client.clone());
+
+        Self {
+            id,
+            context,
+            config,
+            access_log,
+            listener,
+            client,
+            identity: None,
+            started: Instant::now(),
+            protocol: None,
+            destination: None,
+            chain: Vec::new(),
+            warm: None,
+            bound: None,
+            handshake: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            termination: Termination::Aborted,
+            error: None,
+        }
+    }
+
+    fn listener(&self) -> &Listener {
+        &self.config.listeners()[self.listener]
     }
-}
 
-// XXX TODO cleanup
-async fn write_chain_common<S>(stream: &mut S, chain: &[Proxy]) -> Result<TcpStream>
-where
-    S: AsyncWrite + Unpin,
-{
-    assert!(chain.len() > 0);
-    let mut iter = chain.iter().peekable();
-    let first = iter.peek().unwrap();
-    let connection = connect_to_proxy(first).await?;
-
-    loop {
-        let proxy = iter.next().unwrap();
-        let next = match iter.peek() {
-            Some(next) => next,
-            None => break,
+    fn identify(&mut self, client: &Client) {
+        debug!(identity = client.name(), "Client identified");
+        self.context.sessions().set_identity(self.id, client.name());
+        self.identity = Some(client.name().to_owned());
+    }
+
+    fn authenticate(&mut self, credentials: &Credentials) -> Result<()> {
+        let config = self.config.clone();
+
+        let client = config
+            .authenticate(credentials)
+            .ok_or_else(|| AuthError::InvalidCredentials(credentials.username().to_owned()))?;
+
+        self.identify(client);
+        Ok(())
+    }
+
+    // Starts TLS if the listener has it and finds out who the client is from its certificate
+    async fn accept(&mut self, stream: BoxedStream) -> Result<BoxedStream> {
+        let config = self.config.clone();
+        let listener = &config.listeners()[self.listener];
+
+        let tls = match listener.tls() {
+            Some(tls) => tls,
+            None => return Ok(stream),
         };
 
-        match proxy {
-            Proxy::Socks4(..) => match next {
-                &&Proxy::Socks4(ip, port) | &&Proxy::Socks5(ip, port) => {
-                    let command = Socks4Command::Connect(ip, port);
-                    command.write(stream).await?;
-                }
-            },
-            Proxy::Socks5(..) => match next {
-                &&Proxy::Socks4(ip, port) | &&Proxy::Socks5(ip, port) => {
-                    write_socks5_auth(stream).await?;
-                    let command = Socks5Command::Connect(ip, port);
-                    command.write(stream).await?;
-                }
-            },
+        // A client that never finishes the handshake would hold on to the session for good
+        let accepted = timeout(tls.options().handshake_timeout(), tls.server().accept(stream));
+        let (stream, peer) = accepted.await.map_err(IoError::from)??;
+        let verified = tls.options().client_ca().is_some();
+
+        if let Some(client) = peer.and_then(|peer| config.identify(&peer, verified)) {
+            self.identify(client);
         }
+
+        Ok(Box::new(stream))
     }
 
-    Ok(connection)
-}
+    fn profile(&self) -> &str {
+        let client = self.identity.as_deref().and_then(|name| self.config.client(name));
+        client.and_then(Client::profile).unwrap_or(self.listener().profile())
+    }
 
-// XXX TODO cleanup
-async fn read_chain_common<S>(stream: &mut S, chain: &[Proxy]) -> Result<(Ipv4Addr, u16)>
-where
-    S: AsyncRead + Unpin,
-{
-    assert!(chain.len() > 0);
-    let mut iter = chain.iter().peekable();
+    fn select_chain(&mut self, destination: SocketAddr) -> Result<()> {
+        self.destination = Some(destination);
+        self.context.sessions().set_destination(self.id, destination);
 
-    loop {
-        let proxy = iter.next().unwrap();
+        if self.listener().auth() && self.identity.is_none() {
+            Err(AuthError::Required)?;
+        }
 
-        match proxy {
-            Proxy::Socks4(..) => {
-                let reply = Socks4Reply::read(stream).await?;
+        self.config.check_acl(self.listener(), self.identity.as_deref(), destination)?;
+        // A warm tunnel comes with the chain it was built through
+        let warm =
+            self.context.warm_pool().take(&self.config, self.profile(), self.context.proxies());
 
-                if !iter.peek().is_some() {
-                    return Ok((reply.ip(), reply.port()));
-                }
+        self.chain = match warm {
+            Some((chain, tunnel)) => {
+                self.warm = Some(tunnel);
+                chain
             }
-            Proxy::Socks5(..) => {
-                read_socks5_auth_reply(stream).await?;
-                let reply = Socks5Reply::read(stream).await?;
+            None => self.context.selector().make_chain(&self.config, self.profile())?,
+        };
 
-                if !iter.peek().is_some() {
-                    return Ok((reply.ip(), reply.port()));
-                }
-            }
-        }
+        self.context.sessions().set_chain(self.id, &self.chain);
+        debug!(chain = %ChainDisplay(&self.chain), "Chain selected");
+        Ok(())
     }
-}
 
-impl Session {
-    pub fn new(config: Arc<Config>, ip: SocketAddr) -> Self {
-        Self {
-            config,
-            ip,
-        }
+    // From [geoip] or the config, used for the access log and metrics
+    fn exit_tag(&self, key: &str) -> Option<String> {
+        self.chain.last().and_then(|proxy| proxy.tag(key)).map(str::to_owned)
     }
 
-    fn make_chain(&self) -> Vec<Proxy> {
-        let mut final_chain = Vec::new();
-        let mut iter = self.config.chains().iter().peekable();
+    async fn open_chain(&mut self, command: &Command) -> Result<(BoxedStream, SocketAddrV4)> {
+        let (proxy_stream, bound) = match self.warm.take() {
+            Some(tunnel) => tunnel.finish(command).await?,
+            None => {
+                let hops = self.chain.iter().map(Proxy::hop).collect();
+                let connector = ChainConnector::new(hops)?
+                    .with_mode(self.config.server().handshake())
+                    .with_observer(self.context.proxies().clone())
+                    .with_resolver(self.context.resolver().clone());
 
-        while let Some(chain) = iter.next() {
-            let proxy = chain.entries().choose(&mut thread_rng()).unwrap();
-            final_chain.push(proxy.clone());
-        }
+                connector.open(command).await?
+            }
+        };
 
-        final_chain
+        info!(%bound, "Chain established");
+        let (country, asn) = (self.exit_tag("country"), self.exit_tag("asn"));
+        self.context.metrics().chain_established(country.as_deref(), asn.as_deref());
+        self.bound = Some(bound.into());
+        self.context.sessions().set_bound(self.id, bound.into());
+        Ok((proxy_stream, bound))
     }
 
-    async fn handle_socks4(&mut self, client_stream: &mut TcpStream) -> Result<TcpStream> {
+    async fn handle_socks4(&mut self, client_stream: &mut BoxedStream) -> Result<BoxedStream> {
         let command = Socks4Command::read(client_stream).await?;
-        let chain = self.make_chain();
+        debug!(?command, "Received SOCKS4 request");
+        self.select_chain(command.destination().ok_or(SocksError::UnsupportedCommand)?)?;
+        let (proxy_stream, bound) = self.open_chain(&Command::from(&command)).await?;
         let mut buf = vec![];
-        let mut proxy_stream = write_chain_common(&mut buf, &chain).await?;
-        let last_proxy = chain.last().unwrap();
-
-        match last_proxy {
-            Proxy::Socks5(..) => {
-                write_socks5_auth(&mut buf).await?;
-                let command = Socks5Command::from(&command);
-                command.write(&mut buf).await?;
-            }
-            Proxy::Socks4(..) => {
-                command.write(&mut buf).await?;
+        let reply = Socks4Reply::new(*bound.ip(), bound.port());
+        reply.write(&mut buf).await?;
+        client_stream.write_all(&buf).await?;
+        self.handshake = Some(self.started.elapsed());
+        Ok(proxy_stream)
+    }
+
+    // A password is asked for when the listener needs to know clients its TLS didn't identify,
+    // and otherwise whenever one is offered, so clients can be told apart
+    fn socks5_auth_method(&self, methods: &[u8]) -> u8 {
+        let anonymous = self.identity.is_none();
+        let required = anonymous && self.listener().auth();
+        let wanted = anonymous && (required || self.config.has_passwords());
+
+        if wanted && methods.contains(&PASSWORD_AUTH) {
+            PASSWORD_AUTH
+        } else if !required && methods.contains(&NO_AUTH) {
+            NO_AUTH
+        } else {
+            NO_ACCEPTABLE_METHOD
+        }
+    }
+
+    async fn handle_socks5(&mut self, client_stream: &mut BoxedStream) -> Result<BoxedStream> {
+        let request = read_socks5_auth_request(client_stream).await?;
+        let method = self.socks5_auth_method(request.methods());
+        write_socks5_auth_reply(client_stream, method).await?;
+
+        match method {
+            NO_AUTH => {}
+            PASSWORD_AUTH => {
+                let request = Socks5PasswordRequest::read(client_stream).await?;
+                let result = self.authenticate(request.credentials());
+                let status = if result.is_ok() { 0 } else { 1 };
+                write_frame(client_stream, &Socks5PasswordReply::new(status)).await?;
+                result?;
             }
+            _ => Err(Socks5Error::UnsupportedAuthMethod)?,
         }
 
-        proxy_stream.write(&buf).await?;
-        let (ip, port) = read_chain_common(&mut proxy_stream, &chain).await?;
+        let command = Socks5Command::read(client_stream).await?;
+        debug!(?command, "Received SOCKS5 request");
+        self.select_chain(command.destination().ok_or(SocksError::UnsupportedCommand)?)?;
+        let (proxy_stream, bound) = self.open_chain(&Command::from(&command)).await?;
         let mut buf = vec![];
-        let reply = Socks4Reply::new(ip, port);
+        let reply = Socks5Reply::new(*bound.ip(), bound.port());
         reply.write(&mut buf).await?;
-        client_stream.write(&buf).await?;
+        client_stream.write_all(&buf).await?;
+        self.handshake = Some(self.started.elapsed());
         Ok(proxy_stream)
     }
 
-    async fn handle_socks5(&mut self, client_stream: &mut TcpStream) -> Result<TcpStream> {
-        read_socks5_auth_request(client_stream).await?;
-        write_socks5_auth_reply(client_stream).await?;
-        let command = Socks5Command::read(client_stream).await?;
-        let chain = self.make_chain();
-        let mut buf = vec![];
-        let mut proxy_stream = write_chain_common(&mut buf, &chain).await?;
-        let last_proxy = chain.last().unwrap();
+    async fn handle_http(&mut self, client_stream: &mut BoxedStream) -> Result<BoxedStream> {
+        // The C of CONNECT was read to tell HTTP apart from SOCKS
+        let request: HttpConnectRequest = read_frame_after(client_stream, b"C").await?;
+        debug!(target = %request.target(), "Received HTTP CONNECT request");
+        let target = request.target().addr().ok_or(SocksError::UnsupportedCommand)?;
 
-        match last_proxy {
-            Proxy::Socks5(..) => {
-                write_socks5_auth(&mut buf).await?;
-                command.write(&mut buf).await?
+        let connected = async {
+            if let Some(credentials) = request.credentials() {
+                self.authenticate(credentials)?;
             }
-            Proxy::Socks4(..) => {
-                let command = Socks4Command::from(&command);
-                command.write(&mut buf).await?;
+
+            self.select_chain(target.into())?;
+            self.open_chain(&Command::Connect(target.into())).await
+        };
+
+        let proxy_stream = match connected.await {
+            Ok((proxy_stream, _)) => proxy_stream,
+            Err(error) => {
+                write_frame(client_stream, &http_error_reply(&error)).await?;
+                return Err(error);
             }
-        }
+        };
 
-        proxy_stream.write(&buf).await?;
-        let (ip, port) = read_chain_common(&mut proxy_stream, &chain).await?;
-        let mut buf = vec![];
-        let reply = Socks5Reply::new(ip, port);
-        reply.write(&mut buf).await?;
-        client_stream.write(&buf).await?;
+        write_frame(client_stream, &HttpConnectReply::new(200)).await?;
+        self.handshake = Some(self.started.elapsed());
         Ok(proxy_stream)
     }
 
-    async fn run(&mut self, mut client_stream: TcpStream) -> Result<()> {
-        let mut proxy_stream = match read_version(&mut client_stream).await? {
-            SocksVersion::Socks4 => self.handle_socks4(&mut client_stream).await?,
-            SocksVersion::Socks5 => self.handle_socks5(&mut client_stream).await?,
+    async fn run(&mut self, client_stream: BoxedStream) -> Result<Termination> {
+        let mut client_stream = self.accept(client_stream).await?;
+        let protocol = read_protocol(&mut client_stream).await?;
+        self.protocol = Some(protocol);
+
+        if !self.listener().protocols().contains(&protocol) {
+            Err(SocksError::ProtocolDisabled(protocol))?;
+        }
+
+        self.context.metrics().session_accepted(protocol);
+        self.context.sessions().set_protocol(self.id, protocol);
+
+        let proxy_stream = match protocol {
+            Protocol::Socks4 => self.handle_socks4(&mut client_stream).await?,
+            Protocol::Socks5 => self.handle_socks5(&mut client_stream).await?,
+            Protocol::Http => self.handle_http(&mut client_stream).await?,
         };
 
-        let (mut client_read, mut client_write) = client_stream.split();
-        let (mut proxy_read, mut proxy_write) = proxy_stream.split();
+        let (mut client_read, mut client_write) = split(client_stream);
+        let (mut proxy_read, mut proxy_write) = split(proxy_stream);
         let mut proxy_buf = [0u8; 512];
         let mut client_buf = [0u8; 512];
 
@@ -188,32 +311,109 @@ impl Session {
                     let num = num?;
 
                     if num == 0 {
-                        return Ok(());
+                        return Ok(Termination::UpstreamClosed);
                     }
 
-                    client_write.write(&proxy_buf[..num]).await?;
+                    client_write.write_all(&proxy_buf[..num]).await?;
+                    self.bytes_received += num as u64;
+                    self.context.metrics().bytes_received(num);
                 }
 
                 num = client_read.read(&mut client_buf) => {
                     let num = num?;
 
                     if num == 0 {
-                        return Ok(());
+                        return Ok(Termination::ClientClosed);
                     }
 
-                    proxy_write.write(&client_buf[..num]).await?;
+                    proxy_write.write_all(&client_buf[..num]).await?;
+                    self.bytes_sent += num as u64;
+                    self.context.metrics().bytes_sent(num);
                 }
             }
         }
     }
 
-    pub fn spawn_task(mut self, client_stream: TcpStream) {
-        spawn(async move {
-            if let Err(error) = self.run(client_stream).await {
-                eprintln!("[error] [{}] Error: {}", self.ip, error);
+    pub fn spawn_task(mut self, client_stream: BoxedStream, sessions: &mut JoinSet<()>) {
+        let span = info_span!("session", id = self.id, client = %self.client);
+        let id = self.id;
+        let context = self.context.clone();
+
+        let task = async move {
+            info!("Accepted");
+
+            match self.run(client_stream).await {
+                Ok(termination) => self.termination = termination,
+                Err(error) => {
+                    // Alternate form so a failing hop is reported along with what went wrong
+                    let message = format!("{:#}", error);
+                    warn!(error = %message, "Session failed");
+                    self.context.metrics().session_failed(self.protocol, &error);
+                    self.termination = Termination::Error;
+                    self.error = Some(message);
+                }
             }
 
-            eprintln!("[info] [{}] Disconnected.", self.ip);
+            info!(termination = ?self.termination, "Disconnected");
+        };
+
+        let abort = sessions.spawn(task.instrument(span));
+        context.sessions().set_abort(id, abort);
+    }
+}
+
+// Also covers sessions that are aborted at shutdown, which never get past their await point
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.context.metrics().session_closed();
+        self.context.sessions().remove(self.id);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        self.access_log.log(Record {
+            timestamp: timestamp.as_millis() as u64,
+            session: self.id,
+            listener: self.listener().to_string(),
+            client: self.client.clone(),
+            identity: self.identity.take(),
+            protocol: self.protocol,
+            destination: self.destination.map(|destination| destination.to_string()),
+            chain: self.chain.iter().map(|proxy| proxy.to_string()).collect(),
+            exit_country: self.exit_tag("country"),
+            exit_asn: self.exit_tag("asn"),
+            bound: self.bound,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            handshake_ms: self.handshake.map(|handshake| handshake.as_millis() as u64),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            termination: self.termination,
+            error: self.error.take(),
         });
     }
 }
+
+struct ChainDisplay<'a>(&'a [Proxy]);
+
+impl Display for ChainDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        for (i, proxy) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+
+            write!(f, "{}", proxy)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Tells an HTTP client why its CONNECT failed, anything past credentials and the ACL is the chain's
+fn http_error_reply(error: &AnyError) -> HttpConnectReply {
+    if error.downcast_ref::<AuthError>().is_some() {
+        HttpConnectReply::auth_required()
+    } else if error.downcast_ref::<AclError>().is_some() {
+        HttpConnectReply::new(403)
+    } else {
+        HttpConnectReply::new(502)
+    }
+}
@@ -1,4 +1,6 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
@@ -7,24 +9,48 @@ pub enum Error {
     #[error("unsupported version")]
     UnsupportedVersion,
     #[error("protocol error")]
-    ProtocolError,
+    Protocol,
     #[error("unsupported command")]
     UnsupportedCommand,
+    #[error("{0} is not enabled on this listener")]
+    ProtocolDisabled(Protocol),
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub enum SocksVersion {
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
     Socks4,
     Socks5,
+    Http,
 }
 
-pub async fn read_version<S>(stream: &mut S) -> Result<SocksVersion>
+impl Protocol {
+    pub const ALL: [Self; 3] = [Self::Socks4, Self::Socks5, Self::Http];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Socks4 => "socks4",
+            Self::Socks5 => "socks5",
+            Self::Http => "http",
+        }
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.name())
+    }
+}
+
+// The first byte is enough to tell them apart, HTTP requests start with the C of CONNECT
+pub async fn read_protocol<S>(stream: &mut S) -> Result<Protocol>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
 {
     match stream.read_u8().await? {
-        4 => Ok(SocksVersion::Socks4),
-        5 => Ok(SocksVersion::Socks5),
+        4 => Ok(Protocol::Socks4),
+        5 => Ok(Protocol::Socks5),
+        b'C' => Ok(Protocol::Http),
         _ => Err(Error::UnsupportedVersion)?,
     }
 }
@@ -1,9 +1,16 @@
+use crate::codec::{read_frame, read_frame_after, require, write_frame, Decode, Decoded, Encode};
+use crate::hop::{Command, Endpoint, Hop, HopReader, HopWriter, Target};
 use crate::socks::Error as SocksError;
 use crate::socks5::{Socks5Command, Socks5Reply};
-use anyhow::Result;
-use std::net::Ipv4Addr;
+use anyhow::{Error as AnyError, Result};
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const MAX_USER_ID: usize = 255;
+const MAX_HOSTNAME: usize = 255;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -21,52 +28,115 @@ pub enum Socks4CommandType {
 pub enum Socks4Command {
     Connect(Ipv4Addr, u16),
     Bind(Ipv4Addr, u16),
+    // SOCKS4a, the proxy resolves the hostname
+    ConnectDomain(String, u16),
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Socks4Reply {
     ip: Ipv4Addr,
     port: u16,
 }
 
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Socks4Hop {
+    endpoint: Endpoint,
+}
+
 impl Socks4Command {
+    pub fn destination(&self) -> Option<SocketAddr> {
+        match *self {
+            Self::Connect(ip, port) | Self::Bind(ip, port) => Some((ip, port).into()),
+            Self::ConnectDomain(..) => None,
+        }
+    }
+
     pub async fn write<S>(&self, stream: &mut S) -> Result<()>
     where
-        S: AsyncWrite + Unpin,
+        S: AsyncWrite + Unpin + ?Sized,
     {
-        stream.write_u8(4).await?;
-
-        let (ip, port) = match self {
-            &Self::Connect(ip, port) => {
-                stream.write_u8(1).await?;
-                (ip, port)
-            }
-            &Self::Bind(ip, port) => {
-                stream.write_u8(2).await?;
-                (ip, port)
-            }
-        };
-
-        stream.write_u16(port).await?;
-        stream.write_u32(ip.into()).await?;
-        stream.write_u8(0).await?;
-        Ok(())
+        write_frame(stream, self).await
     }
 
+    // The version byte is expected to have been consumed already
     pub async fn read<S>(stream: &mut S) -> Result<Self>
     where
-        S: AsyncRead + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
     {
-        let command_type = stream.read_u8().await?.try_into()?;
-        let port = stream.read_u16().await?;
-        let ip = stream.read_u32().await?.into();
+        read_frame_after(stream, &[4]).await
+    }
+}
+
+impl Encode for Socks4Command {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let (command_type, ip, port, host) = match self {
+            Self::Connect(ip, port) => (1, *ip, *port, None),
+            Self::Bind(ip, port) => (2, *ip, *port, None),
+            // An address of 0.0.0.x tells the hostname follows the user id
+            Self::ConnectDomain(host, port) => (1, Ipv4Addr::new(0, 0, 0, 1), *port, Some(host)),
+        };
 
-        // Ignore userid part
-        while stream.read_u8().await? != 0 {}
+        buf.push(4);
+        buf.push(command_type);
+        buf.extend_from_slice(&port.to_be_bytes());
+        buf.extend_from_slice(&ip.octets());
+        buf.push(0);
 
-        Ok(match command_type {
-            Socks4CommandType::Connect => Self::Connect(ip, port),
-            Socks4CommandType::Bind => Self::Bind(ip, port),
-        })
+        if let Some(host) = host {
+            buf.extend_from_slice(host.as_bytes());
+            buf.push(0);
+        }
+    }
+}
+
+impl Decode for Socks4Command {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        if buf.first().is_some_and(|version| *version != 4) {
+            return Err(SocksError::Protocol)?;
+        }
+
+        if let Some(needed) = require(buf, 8) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        let command_type = buf[1].try_into()?;
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+        // Ignore userid part, but don't buffer an endless one
+        let user_id = &buf[8..];
+
+        let len = match user_id.iter().position(|byte| *byte == 0) {
+            Some(end) => 8 + end + 1,
+            None if user_id.len() >= MAX_USER_ID => return Err(SocksError::Protocol)?,
+            None => return Ok(Decoded::Incomplete(1)),
+        };
+
+        // SOCKS4a marks a hostname after the user id with an address of 0.0.0.x
+        if !matches!(ip.octets(), [0, 0, 0, last] if last != 0) {
+            let command = match command_type {
+                Socks4CommandType::Connect => Self::Connect(ip, port),
+                Socks4CommandType::Bind => Self::Bind(ip, port),
+            };
+
+            return Ok(Decoded::Complete(command, len));
+        }
+
+        let host = &buf[len..];
+
+        let host_len = match host.iter().position(|byte| *byte == 0) {
+            Some(end) => end,
+            None if host.len() >= MAX_HOSTNAME => return Err(SocksError::Protocol)?,
+            None => return Ok(Decoded::Incomplete(1)),
+        };
+
+        if command_type == Socks4CommandType::Bind {
+            Err(SocksError::UnsupportedCommand)?;
+        }
+
+        let host =
+            String::from_utf8(host[..host_len].to_vec()).map_err(|_| SocksError::Protocol)?;
+        Ok(Decoded::Complete(Self::ConnectDomain(host, port), len + host_len + 1))
     }
 }
 
@@ -88,34 +158,79 @@ impl Socks4Reply {
 
     pub async fn read<S>(stream: &mut S) -> Result<Self>
     where
-        S: AsyncRead + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
     {
-        let version = stream.read_u8().await?;
+        read_frame(stream).await
+    }
 
-        if version != 0 {
-            return Err(SocksError::ProtocolError)?;
-        }
+    pub async fn write<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        write_frame(stream, self).await
+    }
+}
+
+impl Encode for Socks4Reply {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(0);
+        buf.push(90);
+        buf.extend_from_slice(&self.port.to_be_bytes());
+        buf.extend_from_slice(&self.ip.octets());
+    }
+}
 
-        let result = stream.read_u8().await?;
+impl Decode for Socks4Reply {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        if buf.first().is_some_and(|version| *version != 0) {
+            return Err(SocksError::Protocol)?;
+        }
 
-        if result != 90 {
+        if let Some(result) = buf.get(1).copied().filter(|result| *result != 90) {
             return Err(Error::RequestFailed(result))?;
         }
 
-        let port = stream.read_u16().await?;
-        let ip = stream.read_u32().await?;
-        Ok(Self::new(ip.into(), port))
+        if let Some(needed) = require(buf, 8) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        Ok(Decoded::Complete(Self::new(ip, port), 8))
+    }
+}
+
+impl Socks4Hop {
+    pub fn new(endpoint: impl Into<Endpoint>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+        }
     }
+}
 
-    pub async fn write<S>(&self, stream: &mut S) -> Result<()>
-    where
-        S: AsyncWrite + Unpin,
-    {
-        stream.write_u8(0).await?;
-        stream.write_u8(90).await?;
-        stream.write_u16(self.port()).await?;
-        stream.write_u32(self.ip().into()).await?;
-        Ok(())
+impl Display for Socks4Hop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "socks4://{}", self.endpoint)
+    }
+}
+
+#[async_trait]
+impl Hop for Socks4Hop {
+    fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    async fn write_request(&self, stream: &mut HopWriter<'_>, command: &Command) -> Result<()> {
+        Socks4Command::from(command).write(stream).await
+    }
+
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> Result<SocketAddrV4> {
+        let reply = Socks4Reply::read(stream).await?;
+        Ok(SocketAddrV4::new(reply.ip(), reply.port()))
+    }
+
+    fn is_refusal(&self, error: &AnyError) -> bool {
+        matches!(error.downcast_ref(), Some(Error::RequestFailed(_)))
     }
 }
 
@@ -126,7 +241,7 @@ impl TryFrom<u8> for Socks4CommandType {
         match value {
             1 => Ok(Socks4CommandType::Connect),
             2 => Ok(Socks4CommandType::Bind),
-            _ => Err(SocksError::ProtocolError),
+            _ => Err(SocksError::Protocol),
         }
     }
 }
@@ -134,8 +249,33 @@ impl TryFrom<u8> for Socks4CommandType {
 impl From<&Socks5Command> for Socks4Command {
     fn from(value: &Socks5Command) -> Self {
         match value {
-            &Socks5Command::Connect(ip, port) => Self::Connect(ip, port),
-            &Socks5Command::Bind(ip, port) => Self::Bind(ip, port),
+            Socks5Command::Connect(ip, port) => Self::Connect(*ip, *port),
+            Socks5Command::Bind(ip, port) => Self::Bind(*ip, *port),
+            Socks5Command::ConnectDomain(host, port) => Self::ConnectDomain(host.clone(), *port),
+        }
+    }
+}
+
+impl From<&Command> for Socks4Command {
+    fn from(value: &Command) -> Self {
+        match value {
+            Command::Connect(Target::Addr(target)) => Self::Connect(*target.ip(), target.port()),
+            Command::Connect(Target::Domain(host, port)) => {
+                Self::ConnectDomain(host.clone(), *port)
+            }
+            Command::Bind(target) => Self::Bind(*target.ip(), target.port()),
+        }
+    }
+}
+
+impl From<&Socks4Command> for Command {
+    fn from(value: &Socks4Command) -> Self {
+        match value {
+            Socks4Command::Connect(ip, port) => Self::Connect(SocketAddrV4::new(*ip, *port).into()),
+            Socks4Command::Bind(ip, port) => Self::Bind(SocketAddrV4::new(*ip, *port)),
+            Socks4Command::ConnectDomain(host, port) => {
+                Self::Connect(Target::Domain(host.clone(), *port))
+            }
         }
     }
 }
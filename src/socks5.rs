@@ -1,20 +1,34 @@
+use crate::auth::Credentials;
+use crate::codec::{read_frame, read_frame_after, require, write_frame, Decode, Decoded, Encode};
+use crate::hop::{Command, Endpoint, Hop, HopReader, HopStream, HopWriter, Target};
 use crate::socks::Error as SocksError;
 use crate::socks4::{Socks4Command, Socks4Reply};
-use anyhow::Result;
-use std::net::Ipv4Addr;
+use anyhow::{Error as AnyError, Result};
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("no authentication rejected")]
+    #[error("auth method rejected")]
     AuthRejected,
     #[error("request failed: {0}")]
     RequestFailed(u8),
     #[error("unsupported auth method")]
     UnsupportedAuthMethod,
+    #[error("username or password rejected")]
+    PasswordRejected,
 }
 
+pub const NO_AUTH: u8 = 0;
+pub const PASSWORD_AUTH: u8 = 2;
+pub const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+// Username and password are limited to 255 bytes each by their length prefix
+pub const MAX_CREDENTIAL: usize = 255;
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum Socks5CommandType {
     Connect,
@@ -25,129 +39,320 @@ pub enum Socks5CommandType {
 pub enum Socks5Command {
     Connect(Ipv4Addr, u16),
     Bind(Ipv4Addr, u16),
+    // Address type 3, the proxy resolves the hostname
+    ConnectDomain(String, u16),
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Socks5AuthRequest {
+    methods: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Socks5AuthReply {
+    method: u8,
+}
+
+// Username/password authentication from RFC 1929
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Socks5PasswordRequest {
+    credentials: Credentials,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Socks5PasswordReply {
+    status: u8,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Socks5Reply {
     ip: Ipv4Addr,
     port: u16,
 }
 
-pub async fn read_socks5_auth_request<S>(stream: &mut S) -> Result<()>
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Socks5Hop {
+    endpoint: Endpoint,
+    credentials: Option<Credentials>,
+}
+
+pub async fn read_socks5_auth_request<S>(stream: &mut S) -> Result<Socks5AuthRequest>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
 {
-    let num_methods = stream.read_u8().await? as usize;
-    let mut methods = Vec::with_capacity(num_methods);
-
-    for _ in 0..num_methods {
-        methods.push(stream.read_u8().await?);
-    }
-
-    if methods.len() != num_methods {
-        return Err(SocksError::ProtocolError)?;
-    }
-
-    if !methods.iter().any(|&x| x == 0) {
-        return Err(Error::UnsupportedAuthMethod)?;
-    }
-
-    Ok(())
+    // The version byte is expected to have been consumed already
+    read_frame_after(stream, &[5]).await
 }
 
-pub async fn write_socks5_auth_reply<S>(stream: &mut S) -> Result<()>
+pub async fn write_socks5_auth_reply<S>(stream: &mut S, method: u8) -> Result<()>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin + ?Sized,
 {
-    stream.write_u8(5).await?;
-    stream.write_u8(0.into()).await?;
+    write_frame(stream, &Socks5AuthReply::new(method)).await?;
     stream.flush().await?;
     Ok(())
 }
 
-pub async fn write_socks5_auth<S>(stream: &mut S) -> Result<()>
+pub async fn write_socks5_auth<S>(stream: &mut S, method: u8) -> Result<()>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin + ?Sized,
 {
-    stream.write_u8(5).await?;
-    stream.write_u8(1).await?;
-    stream.write_u8(0).await?;
-    Ok(())
+    write_frame(stream, &Socks5AuthRequest::new(vec![method])).await
 }
 
-pub async fn read_socks5_auth_reply<S>(stream: &mut S) -> Result<()>
+pub async fn read_socks5_auth_reply<S>(stream: &mut S, method: u8) -> Result<()>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
 {
-    let ver = stream.read_u8().await?;
+    let reply: Socks5AuthReply = read_frame(stream).await?;
 
-    if ver != 5 {
-        return Err(SocksError::ProtocolError)?;
+    if reply.method() != method {
+        return Err(Error::AuthRejected)?;
     }
 
-    let reply = stream.read_u8().await?;
+    Ok(())
+}
 
-    if reply != 0 {
-        return Err(Error::AuthRejected)?;
+impl Socks5AuthRequest {
+    pub fn new(methods: Vec<u8>) -> Self {
+        Self {
+            methods,
+        }
     }
 
-    Ok(())
+    pub fn methods(&self) -> &[u8] {
+        &self.methods
+    }
 }
 
-impl Socks5Command {
-    pub async fn write<S>(&self, stream: &mut S) -> Result<()>
+impl Encode for Socks5AuthRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(5);
+        buf.push(self.methods.len() as u8);
+        buf.extend_from_slice(&self.methods);
+    }
+}
+
+impl Decode for Socks5AuthRequest {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        check_version(buf)?;
+
+        if let Some(needed) = require(buf, 2) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        let len = 2 + buf[1] as usize;
+
+        if let Some(needed) = require(buf, len) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        Ok(Decoded::Complete(Self::new(buf[2..len].to_vec()), len))
+    }
+}
+
+impl Socks5AuthReply {
+    pub fn new(method: u8) -> Self {
+        Self {
+            method,
+        }
+    }
+
+    pub fn method(&self) -> u8 {
+        self.method
+    }
+}
+
+impl Encode for Socks5AuthReply {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(5);
+        buf.push(self.method);
+    }
+}
+
+impl Decode for Socks5AuthReply {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        check_version(buf)?;
+
+        if let Some(needed) = require(buf, 2) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        Ok(Decoded::Complete(Self::new(buf[1]), 2))
+    }
+}
+
+impl Socks5PasswordRequest {
+    pub fn new(credentials: Credentials) -> Self {
+        Self {
+            credentials,
+        }
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub async fn read<S>(stream: &mut S) -> Result<Self>
     where
-        S: AsyncWrite + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
     {
-        stream.write_u8(5).await?;
+        read_frame(stream).await
+    }
+}
 
-        let (ip, port) = match self {
-            &Self::Connect(ip, port) => {
-                stream.write_u8(1).await?;
-                (ip, port)
-            }
-            &Self::Bind(ip, port) => {
-                stream.write_u8(2).await?;
-                (ip, port)
-            }
-        };
+impl Encode for Socks5PasswordRequest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let username = self.credentials.username().as_bytes();
+        let password = self.credentials.password().as_bytes();
+        buf.extend_from_slice(&[1, username.len() as u8]);
+        buf.extend_from_slice(username);
+        buf.push(password.len() as u8);
+        buf.extend_from_slice(password);
+    }
+}
 
-        stream.write_u8(0).await?;
-        stream.write_u8(1).await?;
-        stream.write_u32(ip.into()).await?;
-        stream.write_u16(port).await?;
-        Ok(())
+impl Decode for Socks5PasswordRequest {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        check_password_version(buf)?;
+
+        if let Some(needed) = require(buf, 2) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        let username_end = 2 + buf[1] as usize;
+
+        if let Some(needed) = require(buf, username_end + 1) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        let len = username_end + 1 + buf[username_end] as usize;
+
+        if let Some(needed) = require(buf, len) {
+            return Ok(Decoded::Incomplete(needed));
+        }
+
+        let text =
+            |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| SocksError::Protocol);
+        let username = text(&buf[2..username_end])?;
+        let password = text(&buf[username_end + 1..len])?;
+        Ok(Decoded::Complete(Self::new(Credentials::new(username, password)), len))
+    }
+}
+
+impl Socks5PasswordReply {
+    pub fn new(status: u8) -> Self {
+        Self {
+            status,
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+}
+
+impl Encode for Socks5PasswordReply {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&[1, self.status]);
+    }
+}
+
+impl Decode for Socks5PasswordReply {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        check_password_version(buf)?;
+
+        match buf.get(1) {
+            Some(0) => Ok(Decoded::Complete(Self::new(0), 2)),
+            Some(_) => Err(Error::PasswordRejected)?,
+            None => Ok(Decoded::Incomplete(2 - buf.len())),
+        }
+    }
+}
+
+impl Socks5Command {
+    pub fn destination(&self) -> Option<SocketAddr> {
+        match *self {
+            Self::Connect(ip, port) | Self::Bind(ip, port) => Some((ip, port).into()),
+            Self::ConnectDomain(..) => None,
+        }
+    }
+
+    pub async fn write<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        write_frame(stream, self).await
     }
 
     pub async fn read<S>(stream: &mut S) -> Result<Self>
     where
-        S: AsyncRead + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
     {
-        let version = stream.read_u8().await?;
+        read_frame(stream).await
+    }
+}
 
-        if version != 5 {
-            return Err(SocksError::ProtocolError)?;
-        }
+impl Encode for Socks5Command {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let (command_type, ip, port) = match self {
+            Self::Connect(ip, port) => (1, *ip, *port),
+            Self::Bind(ip, port) => (2, *ip, *port),
+            Self::ConnectDomain(host, port) => {
+                // Hostnames are checked to fit when the config is loaded
+                buf.extend_from_slice(&[5, 1, 0, 3, host.len() as u8]);
+                buf.extend_from_slice(host.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+                return;
+            }
+        };
+
+        buf.extend_from_slice(&[5, command_type, 0, 1]);
+        buf.extend_from_slice(&ip.octets());
+        buf.extend_from_slice(&port.to_be_bytes());
+    }
+}
 
-        let command_type = stream.read_u8().await?.try_into()?;
-        let reserved = stream.read_u8().await?;
+impl Decode for Socks5Command {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        check_version(buf)?;
 
-        if reserved != 0 {
-            return Err(SocksError::ProtocolError)?;
+        let command_type = match buf.get(1) {
+            Some(command_type) => Socks5CommandType::try_from(*command_type)?,
+            None => return Ok(Decoded::Incomplete(2 - buf.len())),
+        };
+
+        // How much is left depends on the address type
+        match buf.get(3) {
+            Some(_) => {}
+            None if buf.get(2).is_some_and(|reserved| *reserved != 0) => Err(SocksError::Protocol)?,
+            None => return Ok(Decoded::Incomplete(4 - buf.len())),
         }
 
-        let address_type = stream.read_u8().await?;
+        if buf[3] == 3 {
+            let (host, port, len) = match decode_domain(&buf[2..])? {
+                Decoded::Complete((host, port), len) => (host, port, 2 + len),
+                Decoded::Incomplete(needed) => return Ok(Decoded::Incomplete(needed)),
+            };
 
-        if address_type != 1 {
-            return Err(SocksError::UnsupportedCommand)?;
+            if command_type == Socks5CommandType::Bind {
+                Err(SocksError::UnsupportedCommand)?;
+            }
+
+            return Ok(Decoded::Complete(Self::ConnectDomain(host, port), len));
         }
 
-        let ip = stream.read_u32().await?.into();
-        let port = stream.read_u16().await?;
+        let (ip, port, len) = match decode_address(&buf[2..])? {
+            Decoded::Complete((ip, port), len) => (ip, port, 2 + len),
+            Decoded::Incomplete(needed) => return Ok(Decoded::Incomplete(needed)),
+        };
 
-        Ok(match command_type {
+        let command = match command_type {
             Socks5CommandType::Connect => Self::Connect(ip, port),
             Socks5CommandType::Bind => Self::Bind(ip, port),
-        })
+        };
+
+        Ok(Decoded::Complete(command, len))
     }
 }
 
@@ -169,51 +374,205 @@ impl Socks5Reply {
 
     pub async fn read<S>(stream: &mut S) -> Result<Self>
     where
-        S: AsyncRead + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        read_frame(stream).await
+    }
+
+    pub async fn write<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
     {
-        let ver = stream.read_u8().await?;
+        write_frame(stream, self).await
+    }
+}
 
-        if ver != 5 {
-            return Err(SocksError::ProtocolError)?;
-        }
+impl Encode for Socks5Reply {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // XXX TODO send error on fail instead of just dc
+        buf.extend_from_slice(&[5, 0, 0, 1]);
+        buf.extend_from_slice(&self.ip.octets());
+        buf.extend_from_slice(&self.port.to_be_bytes());
+    }
+}
 
-        let reply = stream.read_u8().await?;
+impl Decode for Socks5Reply {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        check_version(buf)?;
 
-        if reply != 0 {
-            return Err(Error::RequestFailed(reply))?;
+        match buf.get(1) {
+            Some(0) => {}
+            Some(reply) => return Err(Error::RequestFailed(*reply))?,
+            None => return Ok(Decoded::Incomplete(2 - buf.len())),
         }
 
-        let reserved = stream.read_u8().await?;
+        Ok(match decode_address(&buf[2..])? {
+            Decoded::Complete((ip, port), len) => Decoded::Complete(Self::new(ip, port), 2 + len),
+            Decoded::Incomplete(needed) => Decoded::Incomplete(needed),
+        })
+    }
+}
+
+fn check_version(buf: &[u8]) -> Result<()> {
+    match buf.first() {
+        Some(version) if *version != 5 => Err(SocksError::Protocol)?,
+        _ => Ok(()),
+    }
+}
+
+// The subnegotiation has a version of its own
+fn check_password_version(buf: &[u8]) -> Result<()> {
+    match buf.first() {
+        Some(version) if *version != 1 => Err(SocksError::Protocol)?,
+        _ => Ok(()),
+    }
+}
+
+// The reserved byte, address type and address shared by requests and replies
+fn decode_address(buf: &[u8]) -> Result<Decoded<(Ipv4Addr, u16)>> {
+    if buf.first().is_some_and(|reserved| *reserved != 0) {
+        Err(SocksError::Protocol)?;
+    }
+
+    if buf.get(1).is_some_and(|address_type| *address_type != 1) {
+        Err(SocksError::UnsupportedCommand)?;
+    }
+
+    if let Some(needed) = require(buf, 8) {
+        return Ok(Decoded::Incomplete(needed));
+    }
+
+    let ip = Ipv4Addr::new(buf[2], buf[3], buf[4], buf[5]);
+    let port = u16::from_be_bytes([buf[6], buf[7]]);
+    Ok(Decoded::Complete((ip, port), 8))
+}
+
+// Same as decode_address, with a length prefixed hostname
+fn decode_domain(buf: &[u8]) -> Result<Decoded<(String, u16)>> {
+    if buf.first().is_some_and(|reserved| *reserved != 0) {
+        Err(SocksError::Protocol)?;
+    }
+
+    if let Some(needed) = require(buf, 3) {
+        return Ok(Decoded::Incomplete(needed));
+    }
+
+    let end = 3 + buf[2] as usize;
+
+    if let Some(needed) = require(buf, end + 2) {
+        return Ok(Decoded::Incomplete(needed));
+    }
 
-        if reserved != 0 {
-            return Err(SocksError::ProtocolError)?;
+    let host = String::from_utf8(buf[3..end].to_vec()).map_err(|_| SocksError::Protocol)?;
+    let port = u16::from_be_bytes([buf[end], buf[end + 1]]);
+    Ok(Decoded::Complete((host, port), end + 2))
+}
+
+impl Socks5Hop {
+    pub fn new(endpoint: impl Into<Endpoint>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            credentials: None,
         }
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
 
-        let address_type = stream.read_u8().await?;
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
 
-        if address_type != 1 {
-            return Err(SocksError::UnsupportedCommand)?;
+    // Only the method the hop is configured for is offered
+    fn auth_method(&self) -> u8 {
+        match self.credentials {
+            Some(_) => PASSWORD_AUTH,
+            None => NO_AUTH,
         }
+    }
 
-        let ip = stream.read_u32().await?.into();
-        let port = stream.read_u16().await?;
-        Ok(Self::new(ip, port))
+    async fn write_password<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        match &self.credentials {
+            Some(credentials) => {
+                write_frame(stream, &Socks5PasswordRequest::new(credentials.clone())).await
+            }
+            None => Ok(()),
+        }
     }
 
-    pub async fn write<S>(&self, stream: &mut S) -> Result<()>
+    async fn read_password_reply<S>(&self, stream: &mut S) -> Result<()>
     where
-        S: AsyncWrite + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
     {
-        stream.write_u8(5).await?;
-        stream.write_u8(0).await?; // XXX TODO send error on fail instead of just dc
-        stream.write_u8(0).await?;
-        stream.write_u8(1).await?;
-        stream.write_u32(self.ip().into()).await?;
-        stream.write_u16(self.port()).await?;
+        if self.credentials.is_some() {
+            read_frame::<Socks5PasswordReply, _>(stream).await?;
+        }
+
         Ok(())
     }
 }
 
+impl Display for Socks5Hop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "socks5://{}", self.endpoint)
+    }
+}
+
+#[async_trait]
+impl Hop for Socks5Hop {
+    fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    async fn write_request(&self, stream: &mut HopWriter<'_>, command: &Command) -> Result<()> {
+        write_socks5_auth(stream, self.auth_method()).await?;
+        self.write_password(stream).await?;
+        Socks5Command::from(command).write(stream).await
+    }
+
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> Result<SocketAddrV4> {
+        read_socks5_auth_reply(stream, self.auth_method()).await?;
+        self.read_password_reply(stream).await?;
+        let reply = Socks5Reply::read(stream).await?;
+        Ok(SocketAddrV4::new(reply.ip(), reply.port()))
+    }
+
+    async fn negotiate(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        self.greet(stream).await?;
+        self.finish_negotiation(stream, command).await
+    }
+
+    async fn greet(&self, stream: &mut HopStream<'_>) -> Result<()> {
+        write_socks5_auth(stream, self.auth_method()).await?;
+        read_socks5_auth_reply(stream, self.auth_method()).await?;
+        self.write_password(stream).await?;
+        self.read_password_reply(stream).await
+    }
+
+    async fn finish_negotiation(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        Socks5Command::from(command).write(stream).await?;
+        let reply = Socks5Reply::read(stream).await?;
+        Ok(SocketAddrV4::new(reply.ip(), reply.port()))
+    }
+
+    fn is_refusal(&self, error: &AnyError) -> bool {
+        matches!(error.downcast_ref(), Some(Error::RequestFailed(_)))
+    }
+}
+
 impl TryFrom<u8> for Socks5CommandType {
     type Error = SocksError;
 
@@ -221,7 +580,7 @@ impl TryFrom<u8> for Socks5CommandType {
         match value {
             1 => Ok(Self::Connect),
             2 => Ok(Self::Bind),
-            _ => Err(SocksError::ProtocolError)?,
+            _ => Err(SocksError::Protocol)?,
         }
     }
 }
@@ -229,8 +588,33 @@ impl TryFrom<u8> for Socks5CommandType {
 impl From<&Socks4Command> for Socks5Command {
     fn from(value: &Socks4Command) -> Self {
         match value {
-            &Socks4Command::Connect(ip, port) => Self::Connect(ip, port),
-            &Socks4Command::Bind(ip, port) => Self::Bind(ip, port),
+            Socks4Command::Connect(ip, port) => Self::Connect(*ip, *port),
+            Socks4Command::Bind(ip, port) => Self::Bind(*ip, *port),
+            Socks4Command::ConnectDomain(host, port) => Self::ConnectDomain(host.clone(), *port),
+        }
+    }
+}
+
+impl From<&Command> for Socks5Command {
+    fn from(value: &Command) -> Self {
+        match value {
+            Command::Connect(Target::Addr(target)) => Self::Connect(*target.ip(), target.port()),
+            Command::Connect(Target::Domain(host, port)) => {
+                Self::ConnectDomain(host.clone(), *port)
+            }
+            Command::Bind(target) => Self::Bind(*target.ip(), target.port()),
+        }
+    }
+}
+
+impl From<&Socks5Command> for Command {
+    fn from(value: &Socks5Command) -> Self {
+        match value {
+            Socks5Command::Connect(ip, port) => Self::Connect(SocketAddrV4::new(*ip, *port).into()),
+            Socks5Command::Bind(ip, port) => Self::Bind(SocketAddrV4::new(*ip, *port)),
+            Socks5Command::ConnectDomain(host, port) => {
+                Self::Connect(Target::Domain(host.clone(), *port))
+            }
         }
     }
 }
@@ -0,0 +1,104 @@
+use crate::context::Context;
+use crate::proxies::SavedStats;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec_pretty};
+use std::collections::BTreeMap;
+use std::future::pending;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::fs::{read, rename, write};
+use tokio::select;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not read state file {0}: {1}")]
+    Unreadable(String, serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StateFile {
+    proxies: BTreeMap<String, SavedStats>,
+}
+
+// Takes over what a previous run knew about proxies, a missing file is a first start
+pub async fn load(context: &Context) -> Result<()> {
+    let config = context.reloader().subscribe().borrow().clone();
+
+    let state = match config.state() {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+
+    let content = match read(state.path()).await {
+        Ok(content) => content,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => Err(error)?,
+    };
+
+    let file: StateFile = from_slice(&content)
+        .map_err(|error| Error::Unreadable(state.path().display().to_string(), error))?;
+
+    let saved = file.proxies.len();
+    let restored = context.proxies().restore(file.proxies, state.max_age());
+    info!(path = %state.path().display(), restored, stale = saved - restored, "Loaded proxy state");
+
+    Ok(())
+}
+
+// Written next to the old file first, so a crash can't leave half of it behind
+pub async fn save(context: &Context) -> Result<()> {
+    let config = context.reloader().subscribe().borrow().clone();
+
+    let path = match config.state() {
+        Some(state) => state.path(),
+        None => return Ok(()),
+    };
+
+    let file = StateFile {
+        proxies: context.proxies().save(),
+    };
+
+    let temporary = temporary_path(path);
+    write(&temporary, to_vec_pretty(&file)?).await?;
+    rename(&temporary, path).await?;
+
+    Ok(())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+// Saves every [state] interval, following config reloads
+pub async fn save_periodically(context: Context) {
+    let mut configs = context.reloader().subscribe();
+
+    loop {
+        let interval = configs.borrow_and_update().state().map(|state| state.interval());
+
+        let wait = async {
+            match interval {
+                Some(interval) => sleep(interval).await,
+                None => pending().await,
+            }
+        };
+
+        select! {
+            changed = configs.changed() => match changed {
+                Ok(()) => continue,
+                Err(_) => return,
+            },
+            () = wait => {}
+        }
+
+        if let Err(error) = save(&context).await {
+            warn!(error = format!("{:#}", error), "Could not save proxy state");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::hop::{BoxedStream, Command, Endpoint, Hop, HopReader, HopStream, HopWriter};
+use crate::resolver::Resolver;
+use anyhow::{Error as AnyError, Result};
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::io::Error as IoError;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct Timeouts {
+    connect: Option<Duration>,
+    handshake: Option<Duration>,
+}
+
+// Gives up on a hop that takes too long to accept the connection or to answer its part of the
+// handshake. Running out of time is the hop's own failure, never a refusal.
+pub struct TimeoutHop {
+    inner: Arc<dyn Hop>,
+    timeouts: Timeouts,
+}
+
+impl Timeouts {
+    pub fn new(connect: Option<Duration>, handshake: Option<Duration>) -> Self {
+        Self {
+            connect,
+            handshake,
+        }
+    }
+
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect
+    }
+
+    pub fn handshake(&self) -> Option<Duration> {
+        self.handshake
+    }
+
+    // Leaves the hop alone if there is nothing to limit
+    pub fn wrap(self, inner: Arc<dyn Hop>) -> Arc<dyn Hop> {
+        if self == Self::default() {
+            return inner;
+        }
+
+        Arc::new(TimeoutHop {
+            inner,
+            timeouts: self,
+        })
+    }
+}
+
+async fn limit<T>(
+    duration: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match duration {
+        Some(duration) => timeout(duration, future).await.map_err(IoError::from)?,
+        None => future.await,
+    }
+}
+
+impl Display for TimeoutHop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait]
+impl Hop for TimeoutHop {
+    fn endpoint(&self) -> &Endpoint {
+        self.inner.endpoint()
+    }
+
+    async fn connect(&self, resolver: &Resolver) -> Result<BoxedStream> {
+        limit(self.timeouts.connect, self.inner.connect(resolver)).await
+    }
+
+    fn wraps_stream(&self) -> bool {
+        self.inner.wraps_stream()
+    }
+
+    async fn wrap(&self, stream: BoxedStream) -> Result<BoxedStream> {
+        limit(self.timeouts.handshake, self.inner.wrap(stream)).await
+    }
+
+    async fn write_request(&self, stream: &mut HopWriter<'_>, command: &Command) -> Result<()> {
+        self.inner.write_request(stream, command).await
+    }
+
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> Result<SocketAddrV4> {
+        limit(self.timeouts.handshake, self.inner.read_reply(stream)).await
+    }
+
+    async fn negotiate(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        limit(self.timeouts.handshake, self.inner.negotiate(stream, command)).await
+    }
+
+    async fn greet(&self, stream: &mut HopStream<'_>) -> Result<()> {
+        limit(self.timeouts.handshake, self.inner.greet(stream)).await
+    }
+
+    async fn finish_negotiation(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        limit(self.timeouts.handshake, self.inner.finish_negotiation(stream, command)).await
+    }
+
+    fn is_refusal(&self, error: &AnyError) -> bool {
+        self.inner.is_refusal(error)
+    }
+}
@@ -0,0 +1,428 @@
+use crate::hop::{BoxedStream, Command, Endpoint, Hop, HopReader, HopStream, HopWriter};
+use crate::resolver::Resolver;
+use anyhow::{Error as AnyError, Result};
+use async_trait::async_trait;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{
+    ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, Error as RustlsError,
+    RootCertStore, ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::parse_x509_certificate;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no certificates in {0}")]
+    NoCertificates(PathBuf),
+    #[error("no private key in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("expected a pin like sha256:<64 hex digits>")]
+    InvalidPin,
+    #[error("cert and key have to be given together")]
+    IncompleteClientCert,
+}
+
+pub type Pin = [u8; 32];
+
+// Everything needed to start TLS with one hop, built once when the config is loaded
+pub struct TlsClient {
+    server_name: Option<ServerName<'static>>,
+    connector: TlsConnector,
+}
+
+pub struct TlsHop {
+    inner: Arc<dyn Hop>,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+// What a client's certificate is recognized by
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PeerCertificate {
+    fingerprint: Pin,
+    subject: String,
+}
+
+// Takes whatever certificate the client has, or none, as long as it holds the key to it. The
+// fingerprint is checked after the handshake.
+#[derive(Debug)]
+struct AnyClientVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+// Accepts exactly the certificate with the pinned SHA-256 digest, and if a CA bundle was given
+// as well, only when it also chains up to it
+#[derive(Debug)]
+struct PinnedVerifier {
+    pin: Pin,
+    inner: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+pub fn fingerprint(certificate: &[u8]) -> Pin {
+    Sha256::digest(certificate).into()
+}
+
+// Accepts "sha256:ab:cd:..." as well as bare hex, in either case
+pub fn parse_pin(value: &str) -> Result<Pin> {
+    let digits: String = value.strip_prefix("sha256:").unwrap_or(value).replace(':', "");
+
+    if digits.len() != 64 || !digits.is_ascii() {
+        Err(Error::InvalidPin)?;
+    }
+
+    let mut pin = [0; 32];
+
+    for (index, byte) in pin.iter_mut().enumerate() {
+        let pair = &digits[index * 2..index * 2 + 2];
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| Error::InvalidPin)?;
+    }
+
+    Ok(pin)
+}
+
+// "CN=alice,O=Example" and "CN=alice, O=Example" are the same subject
+pub fn normalize_subject(subject: &str) -> String {
+    subject.split(',').map(str::trim).collect::<Vec<_>>().join(", ")
+}
+
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certificates.is_empty() {
+        Err(Error::NoCertificates(path.to_owned()))?;
+    }
+
+    Ok(certificates)
+}
+
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?;
+    Ok(key.ok_or_else(|| Error::NoPrivateKey(path.to_owned()))?)
+}
+
+pub fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for certificate in load_certificates(path)? {
+        roots.add(certificate)?;
+    }
+
+    Ok(roots)
+}
+
+impl TlsClient {
+    // Without a CA bundle the bundled web roots are trusted, unless a pin is given, which is
+    // then all that is checked
+    pub fn new(
+        sni: Option<&str>,
+        ca: Option<&Path>,
+        client_cert: Option<(&Path, &Path)>,
+        pin: Option<Pin>,
+    ) -> Result<Self> {
+        let provider = Arc::new(default_provider());
+
+        let roots = match ca {
+            Some(ca) => Some(load_roots(ca)?),
+            None if pin.is_some() => None,
+            None => Some(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            }),
+        };
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match pin {
+            Some(pin) => {
+                let inner = match roots {
+                    Some(roots) => Some(
+                        WebPkiServerVerifier::builder_with_provider(
+                            Arc::new(roots),
+                            provider.clone(),
+                        )
+                        .build()?,
+                    ),
+                    None => None,
+                };
+
+                let verifier = PinnedVerifier {
+                    pin,
+                    inner,
+                    provider,
+                };
+
+                builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+            }
+            None => builder.with_root_certificates(roots.unwrap()),
+        };
+
+        let config = match client_cert {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certificates(cert)?, load_private_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let server_name = match sni {
+            Some(sni) => Some(ServerName::try_from(sni.to_owned())?),
+            None => None,
+        };
+
+        Ok(Self {
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    pub fn wrap(&self, inner: Arc<dyn Hop>) -> TlsHop {
+        // Without an SNI the certificate has to be valid for the hop's address or hostname, or
+        // for localhost when the hop is a Unix socket
+        let localhost = || ServerName::try_from("localhost").unwrap();
+
+        let server_name = self.server_name.clone().unwrap_or_else(|| match inner.endpoint() {
+            Endpoint::Tcp(addr) => ServerName::from(IpAddr::from(*addr.ip())),
+            Endpoint::Host(host, _) => {
+                ServerName::try_from(host.clone()).unwrap_or_else(|_| localhost())
+            }
+            Endpoint::Unix(_) => localhost(),
+        });
+
+        TlsHop {
+            inner,
+            server_name,
+            connector: self.connector.clone(),
+        }
+    }
+}
+
+impl TlsServer {
+    // With a client CA bundle clients need a certificate signed by it, without one they are
+    // still asked for a certificate, but may go without
+    pub fn new(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let provider = Arc::new(default_provider());
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let roots = Arc::new(load_roots(client_ca)?);
+                builder.with_client_cert_verifier(
+                    WebPkiClientVerifier::builder_with_provider(roots, provider).build()?,
+                )
+            }
+            None => builder.with_client_cert_verifier(Arc::new(AnyClientVerifier {
+                provider,
+            })),
+        };
+
+        let config = builder.with_single_cert(load_certificates(cert)?, load_private_key(key)?)?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub async fn accept<S>(&self, stream: S) -> Result<(TlsStream<S>, Option<PeerCertificate>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.acceptor.accept(stream).await?;
+
+        let peer = match stream.get_ref().1.peer_certificates() {
+            Some([certificate, ..]) => Some(PeerCertificate::parse(certificate)?),
+            _ => None,
+        };
+
+        Ok((stream, peer))
+    }
+}
+
+impl PeerCertificate {
+    pub fn parse(certificate: &[u8]) -> Result<Self> {
+        let (_, parsed) = parse_x509_certificate(certificate)?;
+
+        Ok(Self {
+            fingerprint: fingerprint(certificate),
+            subject: normalize_subject(&parsed.subject().to_string()),
+        })
+    }
+
+    pub fn fingerprint(&self) -> &Pin {
+        &self.fingerprint
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+}
+
+impl Display for TlsHop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.inner.to_string().replacen("://", "+tls://", 1))
+    }
+}
+
+#[async_trait]
+impl Hop for TlsHop {
+    fn endpoint(&self) -> &Endpoint {
+        self.inner.endpoint()
+    }
+
+    async fn connect(&self, resolver: &Resolver) -> Result<BoxedStream> {
+        self.inner.connect(resolver).await
+    }
+
+    fn wraps_stream(&self) -> bool {
+        true
+    }
+
+    async fn wrap(&self, stream: BoxedStream) -> Result<BoxedStream> {
+        let stream = self.inner.wrap(stream).await?;
+        let stream = self.connector.connect(self.server_name.clone(), stream).await?;
+        Ok(Box::new(stream))
+    }
+
+    async fn write_request(&self, stream: &mut HopWriter<'_>, command: &Command) -> Result<()> {
+        self.inner.write_request(stream, command).await
+    }
+
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> Result<SocketAddrV4> {
+        self.inner.read_reply(stream).await
+    }
+
+    async fn negotiate(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        self.inner.negotiate(stream, command).await
+    }
+
+    async fn greet(&self, stream: &mut HopStream<'_>) -> Result<()> {
+        self.inner.greet(stream).await
+    }
+
+    async fn finish_negotiation(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> Result<SocketAddrV4> {
+        self.inner.finish_negotiation(stream, command).await
+    }
+
+    fn is_refusal(&self, error: &AnyError) -> bool {
+        self.inner.is_refusal(error)
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        if fingerprint(end_entity) != self.pin {
+            return Err(RustlsError::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        match &self.inner {
+            Some(inner) => {
+                inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            }
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for AnyClientVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, RustlsError> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,178 @@
+use crate::chain::{ChainConnector, Tunnel};
+use crate::config::{Config, Proxy, DEFAULT_PROFILE};
+use crate::context::Context;
+use crate::proxies::Proxies;
+use anyhow::Result;
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::iter::repeat_n;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+
+// How often tunnels are checked and the pool topped up, besides right after one was taken
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Hop timeouts are optional, and a proxy that never answers mustn't hold up refills and reloads
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Warmed {
+    config: Weak<Config>,
+    chain: Vec<Proxy>,
+    tunnel: Tunnel,
+}
+
+// Tunnels by profile, oldest first
+#[derive(Default)]
+pub struct WarmPool {
+    tunnels: Mutex<HashMap<String, Vec<Warmed>>>,
+    taken: Notify,
+}
+
+impl Warmed {
+    // Built for the config in use, young enough, through proxies that are still fine and not hung
+    // up on by any of them
+    fn is_usable(&mut self, config: &Arc<Config>, max_age: Duration, proxies: &Proxies) -> bool {
+        self.config.ptr_eq(&Arc::downgrade(config))
+            && self.tunnel.age() < max_age
+            && self.chain.iter().all(|proxy| proxies.is_still_usable(proxy))
+            && self.tunnel.is_alive()
+    }
+}
+
+impl WarmPool {
+    // The oldest tunnel of the profile that is still good, along with its chain. The ones found
+    // broken on the way are dropped.
+    pub fn take(
+        &self,
+        config: &Arc<Config>,
+        profile: &str,
+        proxies: &Proxies,
+    ) -> Option<(Vec<Proxy>, Tunnel)> {
+        let max_age = config.profile(profile)?.warm()?.max_age();
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let warmed = tunnels.get_mut(profile)?;
+        let mut found = None;
+
+        while !warmed.is_empty() {
+            let mut candidate = warmed.remove(0);
+
+            if candidate.is_usable(config, max_age, proxies) {
+                found = Some(candidate);
+                break;
+            }
+
+            debug!(profile, "Discarded warm chain");
+        }
+
+        drop(tunnels);
+        self.taken.notify_one();
+        found.map(|warmed| (warmed.chain, warmed.tunnel))
+    }
+
+    // Hanging on to tunnels after a rotation would keep their chains in use
+    pub fn clear(&self) {
+        self.tunnels.lock().unwrap().clear();
+    }
+
+    pub fn len(&self, profile: &str) -> usize {
+        self.tunnels.lock().unwrap().get(profile).map_or(0, Vec::len)
+    }
+
+    // Drops whatever can't be used anymore and returns how many tunnels each profile is short
+    fn prune(&self, config: &Arc<Config>, proxies: &Proxies) -> Vec<(String, usize)> {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let mut kept = HashMap::new();
+        let mut missing = Vec::new();
+        let names =
+            [DEFAULT_PROFILE].into_iter().chain(config.profiles().keys().map(String::as_str));
+
+        for name in names {
+            let warm = match config.profile(name).and_then(|chains| chains.warm()) {
+                Some(warm) => warm,
+                None => continue,
+            };
+
+            let mut warmed = tunnels.remove(name).unwrap_or_default();
+            warmed.retain_mut(|warmed| warmed.is_usable(config, warm.max_age(), proxies));
+
+            if warmed.len() < warm.size() {
+                missing.push((name.to_owned(), warm.size() - warmed.len()));
+            }
+
+            kept.insert(name.to_owned(), warmed);
+        }
+
+        *tunnels = kept;
+        missing
+    }
+
+    fn add(&self, profile: &str, warmed: Warmed) {
+        self.tunnels.lock().unwrap().entry(profile.to_owned()).or_default().push(warmed);
+    }
+}
+
+async fn warm_up(context: &Context, config: &Arc<Config>, profile: &str) -> Result<()> {
+    let chain = context.selector().make_warm_chain(config, profile)?;
+    let hops = chain.iter().map(Proxy::hop).collect();
+    let connector = ChainConnector::new(hops)?
+        .with_mode(config.server().handshake())
+        .with_observer(context.proxies().clone())
+        .with_resolver(context.resolver().clone());
+
+    let warmed = Warmed {
+        config: Arc::downgrade(config),
+        tunnel: connector.prepare().await?,
+        chain,
+    };
+
+    context.warm_pool().add(profile, warmed);
+    Ok(())
+}
+
+// Keeps every profile with warm chains topped up, following config reloads. After a failure the
+// next attempt waits for the refill interval, so a dead proxy isn't hammered.
+pub async fn keep_warm(context: Context) {
+    let mut configs = context.reloader().subscribe();
+
+    loop {
+        let config = configs.borrow_and_update().clone();
+        let missing = context.warm_pool().prune(&config, context.proxies());
+
+        let (context, config) = (&context, &config);
+        let profiles = missing.iter().flat_map(|(profile, count)| repeat_n(profile, *count));
+
+        let builds = profiles.map(|profile| async move {
+            let result = match timeout(WARM_UP_TIMEOUT, warm_up(context, config, profile)).await {
+                Ok(result) => result,
+                Err(elapsed) => Err(elapsed.into()),
+            };
+
+            if let Err(error) = &result {
+                debug!(profile, error = format!("{:#}", error), "Could not warm up a chain");
+            }
+
+            result.is_ok()
+        });
+
+        // Tunnels still being built for the old config would be thrown away anyway
+        let failed = select! {
+            built = join_all(builds) => built.contains(&false),
+            changed = configs.changed() => match changed {
+                Ok(()) => continue,
+                Err(_) => return,
+            },
+        };
+
+        select! {
+            changed = configs.changed() => if changed.is_err() {
+                return;
+            },
+            () = context.warm_pool().taken.notified(), if !failed => {}
+            () = sleep(REFILL_INTERVAL) => {}
+        }
+    }
+}
@@ -0,0 +1,468 @@
+mod support;
+
+use async_trait::async_trait;
+use rproxychainsd::auth::Credentials;
+use rproxychainsd::chain::Error as ChainError;
+use rproxychainsd::hop::{BoxedStream, Command, Endpoint, Hop, HopReader, HopStream, HopWriter};
+use rproxychainsd::http::HttpHop;
+use rproxychainsd::resolver::Resolver;
+use rproxychainsd::socks5::Socks5Hop;
+use rproxychainsd::timeout::Timeouts;
+use rproxychainsd::{ChainConnector, HandshakeMode, Proxy};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use support::{assert_echo, socks5_connect, Behavior, Daemon, EchoServer, Kind, MockProxy};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::join;
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+const MODES: [HandshakeMode; 2] = [HandshakeMode::Pipelined, HandshakeMode::Stepwise];
+
+fn connector(proxies: &[&MockProxy], mode: HandshakeMode) -> ChainConnector {
+    let hops = proxies.iter().map(|proxy| proxy.hop()).collect();
+    ChainConnector::new(hops).unwrap().with_mode(mode)
+}
+
+// Counts how often the client starts sending again after having heard back, i.e. round trips
+#[derive(Clone, Default)]
+struct Trips(Arc<Mutex<(usize, bool)>>);
+
+struct CountingStream {
+    inner: BoxedStream,
+    trips: Trips,
+}
+
+// Passes everything on to the hop it wraps, counting on the stream to it
+struct CountingHop {
+    inner: Arc<dyn Hop>,
+    trips: Trips,
+}
+
+impl Trips {
+    fn take(&self) -> usize {
+        let mut trips = self.0.lock().unwrap();
+        std::mem::take(&mut trips.0)
+    }
+
+    fn sent(&self) {
+        let mut trips = self.0.lock().unwrap();
+
+        if !trips.1 {
+            trips.0 += 1;
+            trips.1 = true;
+        }
+    }
+
+    fn heard_back(&self) {
+        self.0.lock().unwrap().1 = false;
+    }
+}
+
+impl AsyncRead for CountingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if buf.filled().len() > before {
+            self.trips.heard_back();
+        }
+
+        poll
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        self.trips.sent();
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Display for CountingHop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait]
+impl Hop for CountingHop {
+    fn endpoint(&self) -> &Endpoint {
+        self.inner.endpoint()
+    }
+
+    async fn connect(&self, resolver: &Resolver) -> anyhow::Result<BoxedStream> {
+        let inner = self.inner.connect(resolver).await?;
+
+        Ok(Box::new(CountingStream {
+            inner,
+            trips: self.trips.clone(),
+        }))
+    }
+
+    async fn write_request(
+        &self,
+        stream: &mut HopWriter<'_>,
+        command: &Command,
+    ) -> anyhow::Result<()> {
+        self.inner.write_request(stream, command).await
+    }
+
+    async fn read_reply(&self, stream: &mut HopReader<'_>) -> anyhow::Result<SocketAddrV4> {
+        self.inner.read_reply(stream).await
+    }
+
+    async fn negotiate(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> anyhow::Result<SocketAddrV4> {
+        self.inner.negotiate(stream, command).await
+    }
+
+    async fn greet(&self, stream: &mut HopStream<'_>) -> anyhow::Result<()> {
+        self.inner.greet(stream).await
+    }
+
+    async fn finish_negotiation(
+        &self,
+        stream: &mut HopStream<'_>,
+        command: &Command,
+    ) -> anyhow::Result<SocketAddrV4> {
+        self.inner.finish_negotiation(stream, command).await
+    }
+}
+
+#[tokio::test]
+async fn both_modes_connect() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let third = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+
+    for mode in MODES {
+        let (mut stream, _) =
+            connector(&[&first, &second, &third], mode).connect(echo.addr()).await.unwrap();
+        assert_echo(&mut stream, b"through three hops").await;
+    }
+}
+
+#[tokio::test]
+async fn strict_proxies_need_stepwise() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::RejectEarlyData).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::RejectEarlyData).await;
+    let proxies = [&first, &second];
+
+    assert!(connector(&proxies, HandshakeMode::Pipelined).connect(echo.addr()).await.is_err());
+
+    let (mut stream, _) =
+        connector(&proxies, HandshakeMode::Stepwise).connect(echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"one step at a time").await;
+}
+
+#[tokio::test]
+async fn errors_name_the_failing_hop() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::WrongVersion).await;
+    let third = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+
+    for mode in MODES {
+        let error =
+            connector(&[&first, &second, &third], mode).connect(echo.addr()).await.err().unwrap();
+
+        match error.downcast_ref() {
+            Some(ChainError::HopFailed {
+                index,
+                hop,
+            }) => {
+                assert_eq!(*index, 1);
+                assert_eq!(*hop, second.identity());
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let message = format!("{:#}", error);
+        assert!(
+            message.starts_with(&format!("hop 2 ({}) failed: ", second.identity())),
+            "{}",
+            message
+        );
+    }
+}
+
+#[tokio::test]
+async fn unreachable_first_hop_is_reported() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let hop = proxy.hop();
+    drop(proxy);
+
+    for mode in MODES {
+        let connector = ChainConnector::new(vec![hop.clone()]).unwrap().with_mode(mode);
+        let error = connector.connect(echo.addr()).await.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ChainError::HopFailed {
+                index: 0,
+                ..
+            })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn prepared_tunnels_wait_for_the_last_request() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+
+    for (sent, mode) in MODES.into_iter().enumerate() {
+        let mut tunnel = connector(&[&first, &second], mode).prepare().await.unwrap();
+        assert!(tunnel.is_alive());
+        assert_eq!(second.targets().len(), sent);
+
+        let (mut stream, _) = tunnel.finish(&Command::Connect(echo.addr().into())).await.unwrap();
+        assert_echo(&mut stream, b"the rest of the way").await;
+        assert_eq!(second.targets().len(), sent + 1);
+    }
+
+    // A last hop that hangs up while the tunnel waits
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    let hop: Arc<dyn Hop> = Arc::new(Socks5Hop::new(addr));
+    let connector = ChainConnector::new(vec![hop]).unwrap();
+
+    let (tunnel, stream) = join!(connector.prepare(), async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // The greeting is all a prepared tunnel sends
+        stream.read_exact(&mut [0; 3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream
+    });
+
+    let mut tunnel = tunnel.unwrap();
+    assert!(tunnel.is_alive());
+
+    drop(stream);
+    sleep(Duration::from_millis(50)).await;
+    assert!(!tunnel.is_alive());
+}
+
+#[tokio::test]
+async fn pipelined_chains_take_one_round_trip() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let last = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let trips = Trips::default();
+
+    let counted: Arc<dyn Hop> = Arc::new(CountingHop {
+        inner: first.hop(),
+        trips: trips.clone(),
+    });
+    let connector = ChainConnector::new(vec![counted, second.hop(), last.hop()])
+        .unwrap()
+        .with_mode(HandshakeMode::Pipelined);
+
+    let (mut stream, _) = connector.connect(echo.addr()).await.unwrap();
+    assert_eq!(trips.take(), 1);
+    assert_echo(&mut stream, b"all at once").await;
+    trips.take();
+
+    // The last hop of a prepared tunnel only waits for the request itself
+    let tunnel = connector.prepare().await.unwrap();
+    trips.take();
+    let (mut stream, _) = tunnel.finish(&Command::Connect(echo.addr().into())).await.unwrap();
+    assert_eq!(trips.take(), 1);
+    assert_echo(&mut stream, b"the rest in one go").await;
+}
+
+#[tokio::test]
+async fn refusals_are_attributed_the_same_in_both_modes() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Refuse).await;
+    let third = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let chains: String = [&first, &second, &third].iter().map(|proxy| proxy.chain_toml()).collect();
+
+    for mode in ["pipelined", "stepwise"] {
+        let daemon = Daemon::start_with(&format!("handshake = \"{}\"", mode), &chains).await;
+        assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+        assert_eq!(daemon.handshakes(&first.identity()), (1, 0));
+        assert_eq!(daemon.handshakes(&second.identity()), (0, 0));
+        assert_eq!(daemon.handshakes(&third.identity()), (0, 1));
+        daemon.stop().await;
+    }
+}
+
+#[tokio::test]
+async fn daemon_in_stepwise_mode_relays() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::RejectEarlyData).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::RejectEarlyData).await;
+    let chains = first.chain_toml() + &second.chain_toml();
+    let daemon = Daemon::start_with("handshake = \"stepwise\"", &chains).await;
+
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"strict chain").await;
+
+    drop(stream);
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn http_connect_hops() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Http, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let third = MockProxy::spawn(Kind::Http, Behavior::RejectEarlyData).await;
+
+    for mode in MODES {
+        let (mut stream, _) =
+            connector(&[&first, &second, &third], mode).connect(echo.addr()).await.unwrap();
+        assert_echo(&mut stream, b"connect").await;
+    }
+}
+
+#[tokio::test]
+async fn http_refusal_blames_next_hop() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Http, Behavior::Refuse).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let chains = first.chain_toml() + &second.chain_toml();
+    let daemon = Daemon::start(&chains).await;
+
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(daemon.handshakes(&first.identity()), (0, 0));
+    assert_eq!(daemon.handshakes(&second.identity()), (0, 1));
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn hostnames_are_resolved_by_the_previous_hop() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let third = MockProxy::spawn(Kind::Http, Behavior::Accept).await;
+    let fourth = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let proxies = [&first, &second, &third, &fourth];
+
+    let hops = proxies
+        .iter()
+        .map(|proxy| proxy.identity().replace("127.0.0.1", "localhost"))
+        .map(|proxy| proxy.parse::<Proxy>().unwrap().hop())
+        .collect::<Vec<_>>();
+
+    assert_eq!(hops[0].to_string(), format!("socks5://localhost:{}", first.addr().port()));
+
+    for mode in MODES {
+        let connector = ChainConnector::new(hops.clone()).unwrap().with_mode(mode);
+        let (mut stream, _) = connector.connect(echo.addr()).await.unwrap();
+        assert_echo(&mut stream, b"resolved remotely").await;
+    }
+
+    let requested = |proxy: &MockProxy| format!("localhost:{}", proxy.addr().port());
+    assert_eq!(first.targets(), vec![requested(&second); 2]);
+    assert_eq!(second.targets(), vec![requested(&third); 2]);
+    assert_eq!(third.targets(), vec![requested(&fourth); 2]);
+    assert_eq!(fourth.targets(), vec![echo.addr().to_string(); 2]);
+
+    assert!("socks5://proxy example:1080".parse::<Proxy>().is_err());
+    assert!("socks5://proxy..example:1080".parse::<Proxy>().is_err());
+}
+
+#[tokio::test]
+async fn credentials_are_sent_to_hops() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let chains =
+        format!("{}[[clients]]\nname = \"alice\"\npassword = \"secret\"\n", proxy.chain_toml());
+    let daemon = Daemon::start_with("auth = true\n", &chains).await;
+
+    let hops = |password: &str| -> [Arc<dyn Hop>; 2] {
+        let credentials = Credentials::new("alice".to_owned(), password.to_owned());
+        [
+            Arc::new(Socks5Hop::new(daemon.addr()).with_credentials(credentials.clone())),
+            Arc::new(HttpHop::new(daemon.addr()).with_credentials(credentials)),
+        ]
+    };
+
+    for mode in MODES {
+        for hop in hops("secret") {
+            let connector = ChainConnector::new(vec![hop]).unwrap().with_mode(mode);
+            let (mut stream, _) = connector.connect(echo.addr()).await.unwrap();
+            assert_echo(&mut stream, b"logged in").await;
+        }
+
+        // Rejected credentials are the hop's own fault, not a refusal to reach the target
+        for hop in hops("wrong") {
+            let connector = ChainConnector::new(vec![hop]).unwrap().with_mode(mode);
+            let error = connector.connect(echo.addr()).await.err().unwrap();
+            assert!(matches!(
+                error.downcast_ref(),
+                Some(ChainError::HopFailed {
+                    index: 0,
+                    ..
+                })
+            ));
+        }
+    }
+
+    assert_eq!(proxy.connections(), 4);
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn slow_hops_time_out() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Delay(Duration::from_millis(500))).await;
+    let timeouts = Timeouts::new(None, Some(Duration::from_millis(100)));
+
+    for mode in MODES {
+        let hops = vec![timeouts.wrap(first.hop()), timeouts.wrap(second.hop())];
+        let connector = ChainConnector::new(hops).unwrap().with_mode(mode);
+        let error = connector.connect(echo.addr()).await.err().unwrap();
+
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ChainError::HopFailed {
+                index: 1,
+                ..
+            })
+        ));
+        assert_eq!(error.downcast_ref::<IoError>().map(IoError::kind), Some(ErrorKind::TimedOut));
+    }
+
+    let hops = vec![timeouts.wrap(first.hop())];
+    let (mut stream, _) = ChainConnector::new(hops).unwrap().connect(echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"in time").await;
+}
@@ -0,0 +1,321 @@
+use proptest::prelude::*;
+use rproxychainsd::auth::Credentials;
+use rproxychainsd::codec::{read_frame, Decode, Decoded, Encode};
+use rproxychainsd::hop::Target;
+use rproxychainsd::http::{HttpConnectReply, HttpConnectRequest};
+use rproxychainsd::socks4::{Socks4Command, Socks4Reply};
+use rproxychainsd::socks5::{
+    Socks5AuthReply, Socks5AuthRequest, Socks5Command, Socks5PasswordReply, Socks5PasswordRequest,
+    Socks5Reply,
+};
+use std::fmt::Debug;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Builder;
+
+fn ip() -> impl Strategy<Value = Ipv4Addr> {
+    any::<u32>().prop_map(Ipv4Addr::from)
+}
+
+fn hostname() -> impl Strategy<Value = String> {
+    "[a-z0-9-]{1,63}(\\.[a-z0-9-]{1,63}){0,3}"
+}
+
+// 0.0.0.x means a SOCKS4a hostname follows
+fn socks4_ip() -> impl Strategy<Value = Ipv4Addr> {
+    ip().prop_filter("SOCKS4a marker", |ip| !matches!(ip.octets(), [0, 0, 0, 1..=255]))
+}
+
+fn socks4_command() -> impl Strategy<Value = Socks4Command> {
+    prop_oneof![
+        (socks4_ip(), any::<u16>()).prop_map(|(ip, port)| Socks4Command::Connect(ip, port)),
+        (socks4_ip(), any::<u16>()).prop_map(|(ip, port)| Socks4Command::Bind(ip, port)),
+        (hostname(), any::<u16>())
+            .prop_map(|(host, port)| Socks4Command::ConnectDomain(host, port)),
+    ]
+}
+
+fn socks5_command() -> impl Strategy<Value = Socks5Command> {
+    prop_oneof![
+        (ip(), any::<u16>()).prop_map(|(ip, port)| Socks5Command::Connect(ip, port)),
+        (ip(), any::<u16>()).prop_map(|(ip, port)| Socks5Command::Bind(ip, port)),
+        (hostname(), any::<u16>())
+            .prop_map(|(host, port)| Socks5Command::ConnectDomain(host, port)),
+    ]
+}
+
+fn socks4_reply() -> impl Strategy<Value = Socks4Reply> {
+    (ip(), any::<u16>()).prop_map(|(ip, port)| Socks4Reply::new(ip, port))
+}
+
+fn socks5_reply() -> impl Strategy<Value = Socks5Reply> {
+    (ip(), any::<u16>()).prop_map(|(ip, port)| Socks5Reply::new(ip, port))
+}
+
+fn socks5_auth_request() -> impl Strategy<Value = Socks5AuthRequest> {
+    proptest::collection::vec(any::<u8>(), 0..=255).prop_map(Socks5AuthRequest::new)
+}
+
+fn socks5_auth_reply() -> impl Strategy<Value = Socks5AuthReply> {
+    any::<u8>().prop_map(Socks5AuthReply::new)
+}
+
+// Basic authorization can't tell a colon in the username from the separator
+fn credentials() -> impl Strategy<Value = Credentials> {
+    ("[^:]{0,32}", ".{0,32}").prop_map(|(username, password)| Credentials::new(username, password))
+}
+
+fn socks5_password_request() -> impl Strategy<Value = Socks5PasswordRequest> {
+    credentials().prop_map(Socks5PasswordRequest::new)
+}
+
+fn http_connect_request() -> impl Strategy<Value = HttpConnectRequest> {
+    (ip(), any::<u16>(), proptest::option::of(credentials())).prop_map(|(ip, port, credentials)| {
+        let request = HttpConnectRequest::new(SocketAddrV4::new(ip, port));
+
+        match credentials {
+            Some(credentials) => request.with_credentials(credentials),
+            None => request,
+        }
+    })
+}
+
+fn http_connect_reply() -> impl Strategy<Value = HttpConnectReply> {
+    (200..300u16).prop_map(HttpConnectReply::new)
+}
+
+// Encodes the frame, checks that it decodes back to itself whatever follows it, and that every
+// truncation asks for more input without ever asking for more than the frame has left
+fn check_round_trip<T>(frame: T, trailing: &[u8]) -> Result<(), TestCaseError>
+where
+    T: Encode + Decode + PartialEq + Debug,
+{
+    let mut buf = vec![];
+    frame.encode(&mut buf);
+    let len = buf.len();
+
+    for end in 0..len {
+        match T::decode(&buf[..end]) {
+            Ok(Decoded::Incomplete(needed)) => {
+                prop_assert!(needed > 0 && needed <= len - end, "asked for {} at {}", needed, end)
+            }
+            other => prop_assert!(false, "prefix of {} bytes decoded to {:?}", end, other),
+        }
+    }
+
+    buf.extend_from_slice(trailing);
+    prop_assert_eq!(T::decode(&buf).unwrap(), Decoded::Complete(frame, len));
+    Ok(())
+}
+
+// Writes the frame in small pieces through a tiny pipe, so the reader only ever sees partial
+// input, and checks that nothing past the frame is consumed
+fn check_stream<T>(frame: T, chunk: usize, trailing: Vec<u8>) -> Result<(), TestCaseError>
+where
+    T: Encode + Decode + PartialEq + Debug,
+{
+    let mut buf = vec![];
+    frame.encode(&mut buf);
+    buf.extend_from_slice(&trailing);
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+
+    let (decoded, rest) = runtime.block_on(async {
+        let (mut reader, mut writer) = tokio::io::duplex(chunk);
+
+        let writer = tokio::spawn(async move {
+            for piece in buf.chunks(chunk) {
+                writer.write_all(piece).await.unwrap();
+            }
+        });
+
+        let decoded: T = read_frame(&mut reader).await.unwrap();
+        let mut rest = vec![0; trailing.len()];
+        tokio::io::AsyncReadExt::read_exact(&mut reader, &mut rest).await.unwrap();
+        writer.await.unwrap();
+        (decoded, rest)
+    });
+
+    prop_assert_eq!(decoded, frame);
+    prop_assert_eq!(rest, trailing);
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn socks4_command_round_trip(frame in socks4_command(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn socks4_reply_round_trip(frame in socks4_reply(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn socks5_auth_request_round_trip(frame in socks5_auth_request(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn socks5_auth_reply_round_trip(frame in socks5_auth_reply(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn socks5_password_request_round_trip(frame in socks5_password_request(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn socks5_password_reply_round_trip(trailing in any::<Vec<u8>>()) {
+        check_round_trip(Socks5PasswordReply::new(0), &trailing)?;
+    }
+
+    #[test]
+    fn socks5_command_round_trip(frame in socks5_command(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn socks5_reply_round_trip(frame in socks5_reply(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn http_connect_request_round_trip(frame in http_connect_request(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn http_connect_request_partial_reads(frame in http_connect_request(), chunk in 1..4usize, trailing in any::<Vec<u8>>()) {
+        check_stream(frame, chunk, trailing)?;
+    }
+
+    #[test]
+    fn socks5_password_request_partial_reads(frame in socks5_password_request(), chunk in 1..4usize, trailing in any::<Vec<u8>>()) {
+        check_stream(frame, chunk, trailing)?;
+    }
+
+    #[test]
+    fn http_connect_reply_round_trip(frame in http_connect_reply(), trailing in any::<Vec<u8>>()) {
+        check_round_trip(frame, &trailing)?;
+    }
+
+    #[test]
+    fn http_connect_reply_partial_reads(frame in http_connect_reply(), chunk in 1..4usize, trailing in any::<Vec<u8>>()) {
+        check_stream(frame, chunk, trailing)?;
+    }
+
+    #[test]
+    fn socks4_command_partial_reads(frame in socks4_command(), chunk in 1..4usize, trailing in any::<Vec<u8>>()) {
+        check_stream(frame, chunk, trailing)?;
+    }
+
+    #[test]
+    fn socks5_command_partial_reads(frame in socks5_command(), chunk in 1..4usize, trailing in any::<Vec<u8>>()) {
+        check_stream(frame, chunk, trailing)?;
+    }
+
+    #[test]
+    fn socks5_reply_partial_reads(frame in socks5_reply(), chunk in 1..4usize, trailing in any::<Vec<u8>>()) {
+        check_stream(frame, chunk, trailing)?;
+    }
+
+    #[test]
+    fn arbitrary_input_never_panics(buf in any::<Vec<u8>>()) {
+        let _ = Socks4Command::decode(&buf);
+        let _ = Socks4Reply::decode(&buf);
+        let _ = Socks5AuthRequest::decode(&buf);
+        let _ = Socks5AuthReply::decode(&buf);
+        let _ = Socks5PasswordRequest::decode(&buf);
+        let _ = Socks5PasswordReply::decode(&buf);
+        let _ = Socks5Command::decode(&buf);
+        let _ = Socks5Reply::decode(&buf);
+        let _ = HttpConnectRequest::decode(&buf);
+        let _ = HttpConnectReply::decode(&buf);
+    }
+}
+
+#[test]
+fn socks4_user_id_is_bounded() {
+    let mut buf = vec![4, 1, 0, 80, 127, 0, 0, 1];
+    buf.resize(buf.len() + 254, b'a');
+    assert_eq!(Socks4Command::decode(&buf).unwrap(), Decoded::Incomplete(1));
+    buf.push(b'a');
+    assert!(Socks4Command::decode(&buf).is_err());
+}
+
+#[test]
+fn socks4_user_id_is_skipped() {
+    let buf = [4, 1, 0, 80, 127, 0, 0, 1, b'b', b'o', b'b', 0, 0xff];
+    let command = Socks4Command::Connect(Ipv4Addr::LOCALHOST, 80);
+    assert_eq!(Socks4Command::decode(&buf).unwrap(), Decoded::Complete(command, 12));
+}
+
+#[test]
+fn refusals_are_reported_early() {
+    assert!(Socks4Reply::decode(&[0, 91]).is_err());
+    assert!(Socks5Reply::decode(&[5, 5]).is_err());
+    assert!(Socks5Command::decode(&[4]).is_err());
+    assert!(Socks5PasswordReply::decode(&[1, 1]).is_err());
+    assert!(
+        HttpConnectReply::decode(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").is_err()
+    );
+}
+
+#[test]
+fn http_header_is_bounded() {
+    let mut buf = b"HTTP/1.1 200 OK\r\n".to_vec();
+    buf.resize(8191, b'a');
+    assert_eq!(HttpConnectReply::decode(&buf).unwrap(), Decoded::Incomplete(4));
+    buf.push(b'a');
+    assert!(HttpConnectReply::decode(&buf).is_err());
+}
+
+#[test]
+fn http_connect_request_headers() {
+    let request = b"CONNECT 127.0.0.1:443 HTTP/1.0\r\nUser-Agent: test\r\n\
+                    proxy-authorization: basic YWxpY2U6c2VjcmV0\r\n\r\n";
+
+    let credentials = Credentials::new("alice".to_owned(), "secret".to_owned());
+    let target: SocketAddrV4 = "127.0.0.1:443".parse().unwrap();
+    let expected = HttpConnectRequest::new(target).with_credentials(credentials);
+    assert_eq!(
+        HttpConnectRequest::decode(request).unwrap(),
+        Decoded::Complete(expected, request.len())
+    );
+
+    assert!(HttpConnectRequest::decode(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    assert!(HttpConnectRequest::decode(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").is_err());
+}
+
+#[test]
+fn hostnames_are_left_to_the_proxy() {
+    let encode = |frame: &dyn Encode| {
+        let mut buf = vec![];
+        frame.encode(&mut buf);
+        buf
+    };
+
+    let host = "proxy.test".to_owned();
+    let target = Target::Domain(host.clone(), 80);
+
+    let mut socks4a = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+    socks4a.extend_from_slice(b"proxy.test\0");
+    assert_eq!(encode(&Socks4Command::ConnectDomain(host.clone(), 80)), socks4a);
+
+    let mut socks5 = vec![5, 1, 0, 3, 10];
+    socks5.extend_from_slice(b"proxy.test\0\x50");
+    assert_eq!(encode(&Socks5Command::ConnectDomain(host.clone(), 80)), socks5);
+
+    assert_eq!(
+        encode(&HttpConnectRequest::new(target)),
+        b"CONNECT proxy.test:80 HTTP/1.1\r\nHost: proxy.test:80\r\n\r\n"
+    );
+
+    // Only a CONNECT can be for a hostname
+    let mut bind = socks5.clone();
+    bind[1] = 2;
+    assert!(Socks5Command::decode(&bind).is_err());
+}
@@ -0,0 +1,257 @@
+use rproxychainsd::config::{Config, ProxyType};
+use rproxychainsd::metrics::Metrics;
+use rproxychainsd::proxies::Proxies;
+use rproxychainsd::reload::Reloader;
+use rproxychainsd::selector::Selector;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SERVER: &str = "[server]\nhost = \"127.0.0.1\"\nport = 1080\n";
+
+const PROXIES: &str = "\
+[[proxies]]
+name = \"tor\"
+type = \"socks5\"
+address = \"127.0.0.1:9050\"
+tags = [\"tor\"]
+connect_timeout = 5
+
+[[proxies]]
+name = \"office\"
+type = \"http\"
+host = \"10.0.0.1\"
+port = 3128
+username = \"alice\"
+password = \"secret\"
+weight = 3
+handshake_timeout = 10
+";
+
+fn parse(toml: &str) -> Result<Config, String> {
+    format!("{}{}", SERVER, toml).parse::<Config>().map_err(|error| error.to_string())
+}
+
+fn error(toml: &str) -> String {
+    parse(toml).err().unwrap()
+}
+
+#[test]
+fn chains_refer_to_pools_of_named_proxies() {
+    let config = parse(&format!(
+        "{}[pools]\nexits = [\"tor\", \"office\"]\n\
+         [[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n\
+         [[chains]]\npool = \"exits\"\n\
+         [[profiles.fast.chains]]\npool = \"exits\"\n",
+        PROXIES
+    ))
+    .unwrap();
+
+    let exits = &config.chains()[1];
+    assert_eq!(exits.pool(), Some("exits"));
+
+    let entries = exits.entries();
+    let names: Vec<_> = entries.iter().map(|proxy| proxy.name().unwrap()).collect();
+    assert_eq!(names, ["tor", "office"]);
+    assert_eq!(config.profile("fast").unwrap()[0].entries(), exits.entries());
+
+    let tor = config.proxy("tor").unwrap();
+    assert_eq!(tor.to_string(), "socks5://127.0.0.1:9050");
+    assert_eq!(tor.tags(), ["tor"]);
+    assert_eq!((tor.weight(), tor.credentials()), (1, None));
+    assert_eq!(tor.timeouts().connect(), Some(Duration::from_secs(5)));
+
+    let office = config.proxy("office").unwrap();
+    assert_eq!(office.kind(), ProxyType::Http);
+    assert_eq!(office.credentials().unwrap().username(), "alice");
+    assert_eq!(office.weight(), 3);
+    assert_eq!(office.timeouts().handshake(), Some(Duration::from_secs(10)));
+
+    // Inline tables take the same fields
+    let inline =
+        "[[chains]]\nentries = [{ type = \"socks5\", address = \"unix:/run/tor/socks\" }]\n";
+    let config = parse(inline).unwrap();
+    assert_eq!(config.chains()[0].entries()[0].to_string(), "socks5://unix:/run/tor/socks");
+}
+
+#[test]
+fn proxies_are_looked_up_by_identity() {
+    let config = parse(&format!(
+        "{}[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n\
+         [[profiles.fast.chains]]\nentries = [[\"http\", \"10.0.0.2\", 8080]]\n",
+        PROXIES
+    ))
+    .unwrap();
+
+    // Named proxies count even if no chain uses them, and credentials aren't part of the identity
+    for known in ["socks5://127.0.0.1:1080", "http://10.0.0.2:8080", "http://10.0.0.1:3128"] {
+        assert!(config.contains(&known.parse().unwrap()), "{}", known);
+    }
+
+    for unknown in ["socks4://127.0.0.1:1080", "http://10.0.0.2:8081"] {
+        assert!(!config.contains(&unknown.parse().unwrap()), "{}", unknown);
+    }
+}
+
+#[test]
+fn references_are_validated_with_their_line() {
+    let chain = "[[chains]]\npool = \"exits\"\n";
+
+    let pools = format!("{}[pools]\nexits = [\"tor\", \"vpn\"]\n{}", PROXIES, chain);
+    assert_eq!(error(&pools), "unknown proxy vpn at line 21");
+
+    let chains = format!("{}[pools]\nexits = [\"tor\"]\n[[chains]]\npool = \"fast\"\n", PROXIES);
+    assert_eq!(error(&chains), "unknown pool fast at line 23");
+
+    let duplicate = format!("{}{}", PROXIES, &PROXIES[..PROXIES.find("\n\n").unwrap() + 1]);
+    let duplicate = format!("{}[pools]\nexits = [\"tor\"]\n{}", duplicate, chain);
+    assert_eq!(error(&duplicate), "proxy tor at line 21 is already defined");
+
+    let empty = format!("{}[pools]\nexits = []\n{}", PROXIES, chain);
+    assert_eq!(error(&empty), "pool exits is empty");
+
+    let both = "[[chains]]\npool = \"exits\"\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n";
+    assert!(error(both).contains("either entries, a pool, a file or select"));
+    let unnamed = "[[proxies]]\ntype = \"socks5\"\naddress = \"127.0.0.1:1080\"\n";
+    assert!(error(&format!("{}{}", unnamed, chain)).contains("needs a name"));
+}
+
+#[test]
+fn invalid_entries_say_why() {
+    let chain = |entry: &str| error(&format!("[[chains]]\nentries = [{}]\n", entry));
+
+    assert!(chain("[\"socks5\", \"proxy..example\", 1080]").contains("neither an IPv4 address"));
+    assert!(chain("{ type = \"socks5\", host = \"proxy..example\", port = 1080 }")
+        .contains("neither an IPv4 address"));
+    assert!(chain("{ type = \"socks5\", address = \"127.0.0.1\" }")
+        .contains("host and port, an address or a path"));
+    assert!(chain("{ type = \"socks5\", address = \"127.0.0.1:1080\", colour = \"red\" }")
+        .contains("unknown field `colour`"));
+    assert!(chain("[\"socks5\", \"127.0.0.1\"]").contains("invalid length 2"));
+    assert!(chain("\"socks5://127.0.0.1:1080\"").contains("a proxy like"));
+
+    let credentials = ", address = \"127.0.0.1:1080\", username = \"alice\"";
+    assert!(chain(&format!("{{ type = \"socks5\"{} }}", credentials)).contains("together"));
+    assert!(chain(&format!("{{ type = \"socks4\"{}, password = \"x\" }}", credentials))
+        .contains("socks4 proxies don't take"));
+}
+
+const TAGGED: &str = "\
+[[proxies]]
+name = \"a\"
+type = \"socks5\"
+address = \"10.0.0.1:1080\"
+tags = [\"country=de\", \"provider=acme\"]
+
+[[proxies]]
+name = \"b\"
+type = \"socks5\"
+address = \"10.0.0.2:1080\"
+tags = [\"country=nl\", \"provider=acme\", \"residential\"]
+
+[[proxies]]
+name = \"c\"
+type = \"socks5\"
+address = \"10.0.0.3:1080\"
+tags = [\"country=de\", \"provider=other\"]
+
+[[proxies]]
+name = \"d\"
+type = \"socks5\"
+address = \"10.0.0.4:1080\"
+tags = [\"country=us\"]
+";
+
+#[test]
+fn chains_select_proxies_by_tags() {
+    let selected = |filter: &str| -> Result<Vec<String>, String> {
+        let config = parse(&format!("{}[[chains]]\nselect = \"{}\"\n", TAGGED, filter))?;
+        let entries = config.chains()[0].entries();
+        Ok(entries.iter().map(|proxy| proxy.name().unwrap().to_owned()).collect())
+    };
+
+    assert_eq!(selected("country in [de,nl] and not residential").unwrap(), ["a", "c"]);
+    assert_eq!(selected("not residential or country = us").unwrap(), ["a", "c", "d"]);
+    assert_eq!(selected("not (residential or country = us)").unwrap(), ["a", "c"]);
+    assert_eq!(selected("provider != acme").unwrap(), ["c", "d"]);
+    assert_eq!(selected("residential").unwrap(), ["b"]);
+
+    assert_eq!(selected("country = fr").unwrap_err(), "no proxies match country = fr");
+    assert!(selected("country in [de").unwrap_err().contains("filter ends too early"));
+    assert!(selected("country in de").unwrap_err().contains("unexpected \"de\" in filter"));
+    assert!(selected("country = de and").unwrap_err().contains("filter ends too early"));
+
+    // Pools and entries are narrowed down the same way
+    let pool = format!(
+        "{}[pools]\nexits = [\"a\", \"b\", \"d\"]\n[[chains]]\npool = \"exits\"\nselect = \"country\"\n",
+        TAGGED
+    );
+    let entries = parse(&pool).unwrap().chains()[0].entries();
+    assert_eq!(
+        entries.iter().filter_map(|proxy| proxy.name()).collect::<Vec<_>>(),
+        ["a", "b", "d"]
+    );
+    let pool = pool.replace("select = \"country\"", "select = \"country = de\"");
+    assert_eq!(parse(&pool).unwrap().chains()[0].entries()[0].name(), Some("a"));
+}
+
+#[test]
+fn hops_can_be_kept_apart_by_tags() {
+    let chains =
+        "[[chains]]\nselect = \"provider = acme\"\n[[chains]]\nselect = \"country = de\"\n";
+
+    let config: Config =
+        format!("{}distinct = [\"provider\"]\n{}{}", SERVER, TAGGED, chains).parse().unwrap();
+    let reloader = Reloader::new("config.toml", config);
+    let proxies = Proxies::new(Arc::new(Metrics::new()), reloader.subscribe());
+    let selector = Selector::new(Arc::new(proxies));
+    let config = reloader.subscribe().borrow().clone();
+
+    for _ in 0..20 {
+        let chain = selector.make_chain(&config, "default").unwrap();
+        assert_ne!(chain[0].tag("provider"), chain[1].tag("provider"));
+        assert_eq!(chain[1].name(), Some("c"));
+    }
+
+    let profile = "[profiles.apart]\ndistinct = [\"country\", \"provider\"]\n\
+                   [[profiles.apart.chains]]\nselect = \"country = de\"\n\
+                   [[profiles.apart.chains]]\nselect = \"provider = acme\"\n\
+                   [[profiles.apart.chains]]\nselect = \"country in [de, us]\"\n";
+    let config = Arc::new(parse(&format!("{}{}{}", TAGGED, chains, profile)).unwrap());
+
+    for _ in 0..20 {
+        let chain = selector.make_chain(&config, "apart").unwrap();
+        let names: Vec<_> = chain.iter().filter_map(|proxy| proxy.name()).collect();
+        assert_eq!(names, ["c", "b", "d"]);
+    }
+
+    let profile = profile.replace("country in [de, us]", "country = de");
+    let config = Arc::new(parse(&format!("{}{}{}", TAGGED, chains, profile)).unwrap());
+    let error = selector.make_chain(&config, "apart").err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "no chain of usable proxies has hops that differ in country, provider"
+    );
+
+    // Ten hops can't all differ among nine providers, which is found without trying every order
+    let entry = |index: usize| {
+        format!(
+            "{{ type = \"socks5\", address = \"10.0.{}.{}:1080\", tags = [\"provider=p{}\"] }}",
+            index / 9,
+            index % 9,
+            index % 9
+        )
+    };
+    let single = "[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n";
+    let entries: Vec<_> = (0..45).map(entry).collect();
+    let chain = format!("[[profiles.many.chains]]\nentries = [{}]\n", entries.join(", "));
+    let profile = format!("[profiles.many]\ndistinct = [\"provider\"]\n{}", chain.repeat(10));
+    let config = Arc::new(parse(&format!("{}{}", single, profile)).unwrap());
+    assert!(selector.make_chain(&config, "many").is_err());
+
+    let profile = format!("[profiles.many]\ndistinct = [\"provider\"]\n{}", chain.repeat(9));
+    let config = Arc::new(parse(&format!("{}{}", single, profile)).unwrap());
+    let chain = selector.make_chain(&config, "many").unwrap();
+    let providers: HashSet<_> = chain.iter().map(|proxy| proxy.tag("provider")).collect();
+    assert_eq!(providers.len(), 9);
+}
@@ -0,0 +1,146 @@
+mod support;
+
+use rproxychainsd::config::Config;
+use rproxychainsd::context::Context;
+use rproxychainsd::exit_check::{check_periodically, Verdict};
+use rproxychainsd::reload::Reloader;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use support::{write_mmdb, Behavior, IpEchoServer, Kind, MockProxy, TempDir};
+use tokio::time::sleep;
+
+const SERVER: &str = "[server]\nhost = \"127.0.0.1\"\nport = 1080\n";
+
+fn entry_toml(proxy: &MockProxy, tags: &str) -> String {
+    let scheme = proxy.identity().split("://").next().unwrap().to_owned();
+    format!("{{ type = {:?}, address = \"{}\", tags = [{}] }}", scheme, proxy.addr(), tags)
+}
+
+fn context(config: &str) -> (Context, Arc<Config>) {
+    let context = Context::new(Reloader::new("config.toml", config.parse().unwrap()));
+    let config = context.reloader().subscribe().borrow().clone();
+    (context, config)
+}
+
+fn recorded_verdict(context: &Context, identity: &str) -> Option<String> {
+    let snapshot = context.proxies().snapshot();
+    let (_, stats) = snapshot.into_iter().find(|(proxy, _)| proxy == identity)?;
+    let stats = serde_json::to_value(stats).unwrap();
+    stats["exit"]["verdict"].as_str().map(str::to_owned)
+}
+
+#[tokio::test]
+async fn exits_are_checked_through_the_chain() {
+    let dir = TempDir::new();
+    let database = dir.path().join("test.mmdb");
+    write_mmdb(&database, &[(Ipv4Addr::new(127, 0, 0, 3), 32, "NL", 1136)]);
+
+    let echo = IpEchoServer::spawn(None).await;
+    let good = MockProxy::spawn(Kind::Socks5, Behavior::Egress(Ipv4Addr::new(127, 0, 0, 2))).await;
+    let hidden =
+        MockProxy::spawn(Kind::Socks5, Behavior::Egress(Ipv4Addr::new(127, 0, 0, 4))).await;
+    let transparent = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let misplaced =
+        MockProxy::spawn(Kind::Http, Behavior::Egress(Ipv4Addr::new(127, 0, 0, 3))).await;
+    let refused = MockProxy::spawn(Kind::Socks5, Behavior::Refuse).await;
+
+    let (context, config) = context(&format!(
+        "{}[geoip]\ncountry = {:?}\n[exit_check]\nurl = \"{}\"\ntimeout = 5\n\
+         [[chains]]\nentries = [{}]\n\
+         [[chains]]\nentries = [{}]\n\
+         [[profiles.other.chains]]\nentries = [{}, {}, {}]\n",
+        SERVER,
+        database,
+        echo.url(),
+        entry_toml(&good, ""),
+        entry_toml(&hidden, ""),
+        entry_toml(&transparent, ""),
+        entry_toml(&misplaced, "\"country=de\""),
+        entry_toml(&refused, ""),
+    ));
+
+    let reports: HashMap<_, _> =
+        config.exit_check().unwrap().check_all(&context, &config).await.into_iter().collect();
+    assert_eq!(reports.len(), 5);
+
+    let good_report = &reports[&good.identity()];
+    assert_eq!(good_report.verdict(), Verdict::Ok);
+    assert_eq!(good_report.exit(), Some(Ipv4Addr::new(127, 0, 0, 2)));
+
+    // Proxies only reachable through others are checked through them
+    assert_eq!(reports[&hidden.identity()].verdict(), Verdict::Ok);
+    assert_eq!(reports[&hidden.identity()].exit(), Some(Ipv4Addr::new(127, 0, 0, 4)));
+    assert!(good.targets().contains(&hidden.addr().to_string()), "{:?}", good.targets());
+
+    // Exits from our own address, like a connection without any proxy
+    assert_eq!(reports[&transparent.identity()].verdict(), Verdict::Transparent);
+
+    let misplaced_report = &reports[&misplaced.identity()];
+    assert_eq!(misplaced_report.verdict(), Verdict::Misplaced);
+    assert_eq!(misplaced_report.country(), Some("nl"));
+
+    let refused_report = &reports[&refused.identity()];
+    assert_eq!(refused_report.verdict(), Verdict::Failed);
+    assert!(refused_report.error().is_some());
+
+    assert_eq!(recorded_verdict(&context, &good.identity()).as_deref(), Some("ok"));
+    assert_eq!(recorded_verdict(&context, &misplaced.identity()).as_deref(), Some("misplaced"));
+
+    let metrics = context.metrics().encode().unwrap();
+    let gauge = |proxy: &MockProxy, value| {
+        format!("rproxychainsd_proxy_exit_ok{{proxy=\"{}\"}} {}", proxy.identity(), value)
+    };
+    assert!(metrics.contains(&gauge(&good, 1)), "{}", metrics);
+    assert!(metrics.contains(&gauge(&transparent, 0)), "{}", metrics);
+}
+
+#[tokio::test]
+async fn leaks_are_flagged_by_periodic_checks() {
+    // The endpoint sees our own address in front of the exit, as if a proxy passed it on
+    let echo = IpEchoServer::spawn(Some(Ipv4Addr::LOCALHOST)).await;
+    let leaking = MockProxy::spawn(Kind::Http, Behavior::Egress(Ipv4Addr::new(127, 0, 0, 2))).await;
+
+    let (context, _) = context(&format!(
+        "{}[exit_check]\nurl = \"{}\"\ninterval = 1\ntimeout = 5\n{}",
+        SERVER,
+        echo.url(),
+        leaking.chain_toml()
+    ));
+
+    let checks = tokio::spawn(check_periodically(context.clone()));
+    assert_eq!(recorded_verdict(&context, &leaking.identity()), None);
+
+    for _ in 0..50 {
+        if recorded_verdict(&context, &leaking.identity()).is_some() {
+            break;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    checks.abort();
+    assert_eq!(recorded_verdict(&context, &leaking.identity()).as_deref(), Some("leaking"));
+}
+
+#[test]
+fn urls_must_be_plain_http() {
+    for url in ["https://ifconfig.me/ip", "ifconfig.me", "http://:80/", "http://host:port/"] {
+        let config = format!(
+            "{}[exit_check]\nurl = \"{}\"\n[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 9050]]\n",
+            SERVER, url
+        );
+        let error = config.parse::<Config>().err().unwrap().to_string();
+        assert!(error.contains("exit checks need an http:// URL"), "{}", error);
+    }
+
+    let config = format!(
+        "{}[exit_check]\nurl = \"http://ifconfig.me\"\n[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 9050]]\n",
+        SERVER
+    );
+    let config: Config = config.parse().unwrap();
+    let check = config.exit_check().unwrap();
+    assert_eq!(check.options().timeout(), Duration::from_secs(10));
+    assert_eq!(check.options().interval(), None);
+}
@@ -0,0 +1,110 @@
+mod support;
+
+use rproxychainsd::config::Config;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use support::{socks5_connect, write_mmdb, Behavior, Daemon, EchoServer, Kind, MockProxy, TempDir};
+
+const SERVER: &str = "[server]\nhost = \"127.0.0.1\"\nport = 1080\n";
+
+fn geoip_toml(database: &Path) -> String {
+    format!("[geoip]\ncountry = {:?}\nasn = {:?}\n", database, database)
+}
+
+fn tags(config: &Config, chain: usize) -> Vec<Vec<String>> {
+    config.chains()[chain].entries().iter().map(|proxy| proxy.tags().to_vec()).collect()
+}
+
+#[test]
+fn proxies_are_tagged_with_their_location() {
+    let dir = TempDir::new();
+    let database = dir.path().join("test.mmdb");
+    write_mmdb(
+        &database,
+        &[
+            (Ipv4Addr::new(10, 1, 0, 0), 16, "DE", 3320),
+            (Ipv4Addr::new(10, 2, 0, 0), 16, "NL", 1136),
+        ],
+    );
+
+    let list = dir.path().join("list.txt");
+    fs::write(&list, "10.2.0.5:1080\n10.9.0.1:1080\n").unwrap();
+
+    let config: Config = format!(
+        "{}{}\
+         [[proxies]]\nname = \"de\"\ntype = \"socks5\"\naddress = \"10.1.0.1:1080\"\n\
+         [[proxies]]\nname = \"nl\"\ntype = \"socks5\"\naddress = \"10.2.0.1:1080\"\n\
+         [[proxies]]\nname = \"fixed\"\ntype = \"socks5\"\naddress = \"10.1.0.2:1080\"\ntags = [\"country=fr\"]\n\
+         [[chains]]\nentries = [[\"socks5\", \"10.1.0.3\", 1080], [\"socks5\", \"proxy.example\", 1080]]\n\
+         [[chains]]\nselect = \"country in [de, fr] and not asn = 1136\"\n\
+         [[chains]]\nfile = {:?}\n\
+         [[chains]]\nfile = {:?}\nselect = \"country = nl\"\n",
+        SERVER,
+        geoip_toml(&database),
+        list,
+        list
+    )
+    .parse()
+    .unwrap();
+
+    let geoip = config.geoip().unwrap();
+    assert_eq!(geoip.country(Ipv4Addr::new(10, 1, 200, 1)).as_deref(), Some("de"));
+    assert_eq!(geoip.asn(Ipv4Addr::new(10, 2, 0, 1)), Some(1136));
+    assert_eq!(geoip.country(Ipv4Addr::new(10, 3, 0, 1)), None);
+
+    // Hostnames are left alone, nothing is looked up
+    assert_eq!(tags(&config, 0), [vec!["country=de", "asn=3320"], vec![]]);
+
+    // Tags written by hand win over the database
+    assert_eq!(tags(&config, 1), [vec!["country=de", "asn=3320"], vec!["country=fr", "asn=3320"]]);
+
+    assert_eq!(tags(&config, 2), [vec!["country=nl", "asn=1136"], vec![]]);
+    assert_eq!(tags(&config, 3), [vec!["country=nl", "asn=1136"]]);
+
+    // Lists keep tagging the proxies they read later on
+    fs::write(&list, "10.1.0.9:1080\n10.2.0.9:1080\n").unwrap();
+    config.chains()[3].list().unwrap().reload().unwrap();
+    let entries = config.chains()[3].entries();
+    assert_eq!(
+        entries.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        ["socks5://10.2.0.9:1080"]
+    );
+
+    let missing = format!(
+        "{}{}[[chains]]\nselect = \"country\"\n",
+        SERVER,
+        geoip_toml(&dir.path().join("missing.mmdb"))
+    );
+    let error = missing.parse::<Config>().err().unwrap().to_string();
+    assert!(error.contains("could not read GeoIP database"), "{}", error);
+
+    let empty =
+        format!("{}[geoip]\n[[chains]]\nentries = [[\"socks5\", \"10.1.0.1\", 1080]]\n", SERVER);
+    assert!(empty.parse::<Config>().err().unwrap().to_string().contains("country or asn database"));
+}
+
+#[tokio::test]
+async fn exits_are_counted_by_location() {
+    let dir = TempDir::new();
+    let database = dir.path().join("test.mmdb");
+    write_mmdb(&database, &[(Ipv4Addr::new(127, 0, 0, 0), 8, "DE", 3320)]);
+
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let chains = format!("{}{}", geoip_toml(&database), proxy.chain_toml());
+    let daemon = Daemon::start(&chains).await;
+
+    for _ in 0..2 {
+        socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    }
+
+    let metrics = daemon.context().metrics().encode().unwrap();
+    assert!(
+        metrics.contains("rproxychainsd_chains_established_total{country=\"de\",asn=\"3320\"} 2"),
+        "{}",
+        metrics
+    );
+
+    daemon.stop().await;
+}
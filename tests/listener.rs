@@ -0,0 +1,403 @@
+mod support;
+
+use anyhow::Result;
+use rproxychainsd::config::Config;
+use std::fs::metadata;
+use std::net::SocketAddrV4;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+use support::{
+    assert_echo, free_port, http_connect, socket_path, socks4_connect, socks5_connect,
+    socks5_login, socks5_request, unix_connect, Behavior, Daemon, EchoServer, Kind, MockProxy, Pki,
+    UnixMockProxy,
+};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+
+fn tls_toml(pki: &Pki, client_ca: bool) -> String {
+    let client_ca = if client_ca { format!("client_ca = {:?}\n", pki.ca()) } else { String::new() };
+
+    format!(
+        "[server.tls]\ncert = {:?}\nkey = {:?}\n{}",
+        pki.server_cert(),
+        pki.server_key(),
+        client_ca
+    )
+}
+
+async fn tls_socks5_connect(
+    pki: &Pki,
+    daemon: &Daemon,
+    client_cert: bool,
+    target: SocketAddrV4,
+) -> Result<TlsStream<TcpStream>> {
+    let mut stream = pki.connect(daemon.addr(), client_cert).await?;
+    socks5_request(&mut stream, target).await?;
+    Ok(stream)
+}
+
+#[tokio::test]
+async fn tls_listener_without_client_certificates() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let daemon = Daemon::start_with(&tls_toml(&pki, false), &proxy.chain_toml()).await;
+
+    let mut stream = tls_socks5_connect(&pki, &daemon, false, echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"socks over tls").await;
+}
+
+#[tokio::test]
+async fn tls_handshakes_time_out() {
+    let pki = Pki::generate();
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let server = format!("{}handshake_timeout = 1\n", tls_toml(&pki, false));
+    let daemon = Daemon::start_with(&server, &proxy.chain_toml()).await;
+
+    // Connected, but never says hello
+    let mut stream = TcpStream::connect(daemon.addr()).await.unwrap();
+    let closed = timeout(Duration::from_secs(5), stream.read(&mut [0; 1])).await.unwrap();
+    assert_eq!(closed.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn clients_are_identified_by_subject_and_routed_to_their_profile() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let default = MockProxy::spawn(Kind::Socks5, Behavior::Refuse).await;
+    let trusted = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+
+    let chains = format!(
+        "{}[[clients]]\nname = \"alice\"\nsubject = \"CN=test client\"\nprofile = \"trusted\"\n\
+         [[profiles.trusted.chains]]\nentries = [[\"socks5\", \"127.0.0.1\", {}]]\n",
+        default.chain_toml(),
+        trusted.addr().port()
+    );
+
+    let daemon = Daemon::start_with(&tls_toml(&pki, true), &chains).await;
+    let mut stream = tls_socks5_connect(&pki, &daemon, true, echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"hello alice").await;
+    assert_eq!(trusted.connections(), 1);
+    assert_eq!(default.connections(), 0);
+
+    let sessions = serde_json::to_value(daemon.context().sessions().list()).unwrap();
+    assert_eq!(sessions[0]["identity"], "alice");
+
+    // The CA requires a certificate
+    assert!(tls_socks5_connect(&pki, &daemon, false, echo.addr()).await.is_err());
+}
+
+#[tokio::test]
+async fn clients_are_identified_by_fingerprint_without_a_ca() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+
+    let chains = format!(
+        "{}[[clients]]\nname = \"bob\"\nfingerprint = \"{}\"\n",
+        proxy.chain_toml(),
+        pki.client_pin()
+    );
+
+    let server = format!("auth = true\n{}", tls_toml(&pki, false));
+    let daemon = Daemon::start_with(&server, &chains).await;
+    let mut stream = tls_socks5_connect(&pki, &daemon, true, echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"hello bob").await;
+
+    // With auth required, going without a certificate isn't enough
+    assert!(tls_socks5_connect(&pki, &daemon, false, echo.addr()).await.is_err());
+    assert_eq!(proxy.connections(), 1);
+}
+
+#[tokio::test]
+async fn unknown_client_certificates_are_rejected() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+
+    let chains = format!(
+        "{}[[clients]]\nname = \"carol\"\nfingerprint = \"{}\"\n",
+        proxy.chain_toml(),
+        pki.server_pin()
+    );
+
+    let server = format!("auth = true\n{}", tls_toml(&pki, true));
+    let daemon = Daemon::start_with(&server, &chains).await;
+    assert!(tls_socks5_connect(&pki, &daemon, true, echo.addr()).await.is_err());
+    assert_eq!(proxy.connections(), 0);
+}
+
+#[tokio::test]
+async fn acl_rules_match_clients_and_destinations() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let other = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+
+    let chains = format!(
+        "{}[[clients]]\nname = \"alice\"\nsubject = \"CN=test client\"\n\
+         [[acl]]\naction = \"allow\"\nclients = [\"alice\"]\nports = [{}]\n\
+         [[acl]]\naction = \"deny\"\ndestinations = [\"127.0.0.0/8\"]\n",
+        proxy.chain_toml(),
+        echo.addr().port()
+    );
+
+    let daemon = Daemon::start_with(&tls_toml(&pki, true), &chains).await;
+    let mut stream = tls_socks5_connect(&pki, &daemon, true, echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"allowed").await;
+    assert!(tls_socks5_connect(&pki, &daemon, true, other.addr()).await.is_err());
+    assert_eq!(proxy.connections(), 1);
+}
+
+#[tokio::test]
+async fn listeners_have_their_own_protocols_and_profiles() {
+    let echo = EchoServer::spawn().await;
+    let default = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let fast = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let port = free_port().await;
+    let lan = SocketAddrV4::new([127, 0, 0, 1].into(), port);
+
+    let chains = format!(
+        "{}[[profiles.fast.chains]]\nentries = [[\"socks4\", \"127.0.0.1\", {}]]\n\
+         [[listeners]]\nhost = \"127.0.0.1\"\nport = {}\nprotocols = [\"socks5\", \"http\"]\n\
+         profile = \"fast\"\n",
+        default.chain_toml(),
+        fast.addr().port(),
+        port
+    );
+
+    let daemon = Daemon::start_with("", &chains).await;
+
+    let mut stream = socks4_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"default").await;
+    assert_eq!((default.connections(), fast.connections()), (1, 0));
+
+    let mut stream = socks5_connect(lan, echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"fast").await;
+    let mut stream = http_connect(lan, echo.addr(), None).await.unwrap();
+    assert_echo(&mut stream, b"fast over http").await;
+    assert_eq!((default.connections(), fast.connections()), (1, 2));
+
+    assert!(socks4_connect(lan, echo.addr()).await.is_err());
+    assert_eq!(fast.connections(), 2);
+}
+
+#[tokio::test]
+async fn listeners_can_require_passwords() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let trusted = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+
+    let chains = format!(
+        "{}[[clients]]\nname = \"alice\"\npassword = \"secret\"\nprofile = \"trusted\"\n\
+         [[profiles.trusted.chains]]\nentries = [[\"socks5\", \"127.0.0.1\", {}]]\n",
+        proxy.chain_toml(),
+        trusted.addr().port()
+    );
+
+    let daemon = Daemon::start_with("auth = true\n", &chains).await;
+    let addr = daemon.addr();
+
+    let mut stream = socks5_login(addr, echo.addr(), "alice", "secret").await.unwrap();
+    assert_echo(&mut stream, b"socks5 login").await;
+    let mut stream = http_connect(addr, echo.addr(), Some(("alice", "secret"))).await.unwrap();
+    assert_echo(&mut stream, b"http login").await;
+
+    assert!(socks5_login(addr, echo.addr(), "alice", "wrong").await.is_err());
+    assert!(socks5_connect(addr, echo.addr()).await.is_err());
+    assert!(socks4_connect(addr, echo.addr()).await.is_err());
+    assert!(http_connect(addr, echo.addr(), None).await.is_err());
+    assert!(http_connect(addr, echo.addr(), Some(("bob", "secret"))).await.is_err());
+    assert_eq!((proxy.connections(), trusted.connections()), (0, 2));
+}
+
+#[tokio::test]
+async fn http_clients_are_told_why_connect_failed() {
+    let echo = EchoServer::spawn().await;
+    let other = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Refuse).await;
+
+    let chains = format!(
+        "{}[[clients]]\nname = \"alice\"\npassword = \"secret\"\n\
+         [[acl]]\naction = \"deny\"\nports = [{}]\n",
+        proxy.chain_toml(),
+        other.addr().port()
+    );
+
+    let daemon = Daemon::start_with("auth = true\n", &chains).await;
+    let (addr, target) = (daemon.addr(), echo.addr());
+    let status = move |login| async move {
+        http_connect(addr, target, login).await.err().unwrap().to_string()
+    };
+
+    for login in [None, Some(("alice", "wrong"))] {
+        let reply = status(login).await;
+        assert!(reply.contains("HTTP/1.1 407"), "{}", reply);
+        assert!(reply.contains("Proxy-Authenticate: Basic"), "{}", reply);
+    }
+
+    let reply = status(Some(("alice", "secret"))).await;
+    assert!(reply.contains("HTTP/1.1 502"), "{}", reply);
+
+    let denied = http_connect(daemon.addr(), other.addr(), Some(("alice", "secret"))).await;
+    let reply = denied.err().unwrap().to_string();
+    assert!(reply.contains("HTTP/1.1 403"), "{}", reply);
+    assert_eq!(proxy.connections(), 1);
+}
+
+#[tokio::test]
+async fn listener_acl_rules_come_first() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let port = free_port().await;
+    let restricted = SocketAddrV4::new([127, 0, 0, 1].into(), port);
+
+    let chains = format!(
+        "{}[[listeners]]\nhost = \"127.0.0.1\"\nport = {}\n\
+         acl = [{{ action = \"deny\", ports = [{}] }}]\n\
+         [[acl]]\naction = \"allow\"\n",
+        proxy.chain_toml(),
+        port,
+        echo.addr().port()
+    );
+
+    let daemon = Daemon::start_with("", &chains).await;
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"allowed").await;
+    assert!(socks5_connect(restricted, echo.addr()).await.is_err());
+    assert_eq!(proxy.connections(), 1);
+}
+
+#[tokio::test]
+async fn unix_listeners_have_their_permissions_and_are_removed() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let path = socket_path();
+
+    let chains = format!("{}[[listeners]]\npath = {:?}\nmode = 0o600\n", proxy.chain_toml(), path);
+
+    let daemon = Daemon::start_with("", &chains).await;
+    let mut stream = unix_connect(&path).await.unwrap();
+    socks5_request(&mut stream, echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"over a unix socket").await;
+    assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let sessions = serde_json::to_value(daemon.context().sessions().list()).unwrap();
+    assert_eq!(sessions[0]["client"], format!("unix:{}", path.display()));
+
+    drop(stream);
+    daemon.stop().await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn unix_sockets_can_be_the_first_hop() {
+    let echo = EchoServer::spawn().await;
+    let tor = UnixMockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let proxy = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+
+    let chains = format!(
+        "[[chains]]\nentries = [{{ type = \"socks5\", path = {:?} }}]\n{}",
+        tor.path(),
+        proxy.chain_toml()
+    );
+
+    let daemon = Daemon::start(&chains).await;
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"through tor").await;
+    assert_eq!((tor.connections(), proxy.connections()), (1, 1));
+
+    let identity = format!("socks5://unix:{}", tor.path().display());
+    assert_eq!(daemon.handshakes(&identity), (1, 0));
+}
+
+#[test]
+fn client_references_are_validated() {
+    let pki = Pki::generate();
+    let chains = "[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n";
+
+    let config = |server: &str, rest: &str| {
+        format!("[server]\nhost = \"127.0.0.1\"\nport = 1080\n{}{}{}", server, chains, rest)
+            .parse::<Config>()
+            .err()
+            .map(|error| error.to_string())
+    };
+
+    let subject = "[[clients]]\nname = \"alice\"\nsubject = \"CN=test client\"\n";
+    let fingerprint =
+        format!("[[clients]]\nname = \"alice\"\nfingerprint = \"{}\"\n", pki.client_pin());
+
+    assert_eq!(config(&tls_toml(&pki, true), subject), None);
+    assert!(config(&tls_toml(&pki, false), subject).unwrap().contains("client_ca"));
+    assert!(config("", "[[clients]]\nname = \"alice\"\n")
+        .unwrap()
+        .contains("subject, fingerprint or password"));
+    assert!(config("", &fingerprint.repeat(2)).unwrap().contains("more than once"));
+
+    let profile = format!("{}profile = \"fast\"\n", fingerprint);
+    assert!(config("", &profile).unwrap().contains("unknown profile fast"));
+
+    let acl = "[[acl]]\naction = \"deny\"\nclients = [\"mallory\"]\n";
+    assert!(config("", acl).unwrap().contains("unknown client mallory"));
+    let default = format!("[[profiles.default.chains]]\n{}", &chains[11..]);
+    assert!(config("", &default).unwrap().contains("taken"));
+}
+
+#[test]
+fn listeners_are_validated() {
+    let chains = "[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n";
+
+    let config = |toml: &str| {
+        format!("{}{}", toml, chains).parse::<Config>().err().map(|error| error.to_string())
+    };
+
+    let listener = "[[listeners]]\nhost = \"127.0.0.1\"\nport = 1080\n";
+    assert_eq!(config(&format!("[server]\n{}", listener)), None);
+    assert!(config("[server]\n").unwrap().contains("no listeners"));
+    assert!(config("[server]\nport = 1080\n").unwrap().contains("together"));
+
+    let duplicate = format!("[server]\nhost = \"127.0.0.1\"\nport = 1080\n{}", listener);
+    assert!(config(&duplicate).unwrap().contains("more than one listener"));
+
+    let profile = format!("[server]\n{}profile = \"fast\"\n", listener);
+    assert!(config(&profile).unwrap().contains("unknown profile fast"));
+
+    let unix = "[[listeners]]\npath = \"/tmp/proxy.sock\"\n";
+    assert_eq!(config(&format!("[server]\n{}", unix)), None);
+    assert!(config(&format!("[server]\n{}", unix.repeat(2)))
+        .unwrap()
+        .contains("unix:/tmp/proxy.sock"));
+    let both = format!("[server]\n{}host = \"127.0.0.1\"\n", unix);
+    assert!(config(&both).unwrap().contains("host and port or a path"));
+}
+
+#[test]
+fn unix_proxies_are_validated() {
+    let config = |chains: &str| {
+        format!("[server]\nhost = \"127.0.0.1\"\nport = 1080\n{}", chains)
+            .parse::<Config>()
+            .err()
+            .map(|error| error.to_string())
+    };
+
+    let unix = "[[chains]]\nentries = [{ type = \"socks5\", path = \"/run/tor/socks\" }]\n";
+    let tcp = "[[chains]]\nentries = [[\"socks5\", \"127.0.0.1\", 1080]]\n";
+    assert_eq!(config(&format!("{}{}", unix, tcp)), None);
+
+    let error = config(&format!("{}{}", tcp, unix)).unwrap();
+    assert!(error.contains("socks5://unix:/run/tor/socks"));
+    assert!(error.contains("only be in the first chain"));
+
+    let profile = format!(
+        "{}[[profiles.tor.chains]]\n{}[[profiles.tor.chains]]\n{}",
+        tcp,
+        &tcp[11..],
+        &unix[11..]
+    );
+    assert!(config(&profile).unwrap().contains("only be in the first chain"));
+
+    let both = "[[chains]]\nentries = [{ type = \"socks5\", path = \"/a\", port = 1 }]\n";
+    assert!(config(both).is_some());
+}
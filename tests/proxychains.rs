@@ -0,0 +1,75 @@
+use rproxychainsd::config::Config;
+use rproxychainsd::proxychains::{export, import};
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+fn golden(name: &str) -> String {
+    let path =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/proxychains").join(name);
+    read_to_string(&path).unwrap_or_else(|error| panic!("{}: {}", path.display(), error))
+}
+
+#[test]
+fn proxychains_conf_is_imported() {
+    for name in ["strict", "dynamic", "random"] {
+        let imported = import(&golden(&format!("{}.conf", name))).unwrap();
+        assert_eq!(imported, golden(&format!("{}.toml", name)), "{}.conf", name);
+    }
+}
+
+#[test]
+fn config_is_exported() {
+    for name in ["export", "strict-export"] {
+        let config: Config = golden(&format!("{}.toml", name)).parse().unwrap();
+        assert_eq!(export(&config).unwrap(), golden(&format!("{}.conf", name)), "{}.toml", name);
+    }
+
+    // Importing what was exported gives the same chains back
+    let config: Config = golden("strict-export.toml").parse().unwrap();
+    let imported: Config = import(&export(&config).unwrap()).unwrap().parse().unwrap();
+    assert_eq!(export(&imported).unwrap(), export(&config).unwrap());
+}
+
+#[test]
+fn unsupported_input_is_rejected() {
+    let message = |result: anyhow::Result<String>| result.err().unwrap().to_string();
+
+    assert_eq!(
+        message(import("strict_chain\n[ProxyList]\nraw 127.0.0.1 8080\n")),
+        "unsupported proxy type raw at line 3"
+    );
+    assert_eq!(
+        message(import("[ProxyList]\nsocks5 127.0.0.1\n")),
+        "invalid proxy at line 2, expected \"type host port [user [pass]]\""
+    );
+    assert_eq!(
+        message(import("random_chain\nchain_len = 0\n[ProxyList]\nsocks5 127.0.0.1 1080\n")),
+        "invalid chain_len at line 2"
+    );
+    assert_eq!(message(import("strict_chain\n")), "no proxies in [ProxyList]");
+
+    let server = "[server]\nhost = \"127.0.0.1\"\nport = 1080\n";
+
+    let config: Config = format!(
+        "{}[[chains]]\nentries = [[\"socks5\", \"10.0.0.1\", 1080], [\"socks5\", \"10.0.0.2\", 1080]]\n\
+         [[chains]]\nentries = [[\"socks5\", \"10.0.0.3\", 1080]]\n",
+        server
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(
+        message(export(&config)),
+        "only chains of a single proxy each, or chains that all have the same proxies, can be exported"
+    );
+
+    let config: Config = format!(
+        "{}[[chains]]\nentries = [{{ type = \"socks5\", path = \"/run/tor/socks\" }}]\n",
+        server
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(
+        message(export(&config)),
+        "proxy socks5://unix:/run/tor/socks can't be written to proxychains.conf"
+    );
+}
@@ -0,0 +1,308 @@
+mod support;
+
+use rproxychainsd::admin::{self, Mark, Request};
+use rproxychainsd::chain::HandshakeObserver;
+use rproxychainsd::metrics::{serve, Metrics};
+use rproxychainsd::server::Shutdown;
+use rproxychainsd::warm_pool::keep_warm;
+use std::sync::Arc;
+use std::time::Duration;
+use support::{
+    assert_echo, eventually, free_port, socket_path, socks4_connect, socks5_connect, Behavior,
+    Daemon, EchoServer, Kind, MockProxy,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::{sleep, timeout};
+
+fn chains(proxies: &[&MockProxy]) -> String {
+    proxies.iter().map(|proxy| proxy.chain_toml()).collect()
+}
+
+#[tokio::test]
+async fn socks5_client_through_mixed_chain() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let daemon = Daemon::start(&chains(&[&first, &second])).await;
+
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"hello through two hops").await;
+    assert_eq!((first.connections(), second.connections()), (1, 1));
+    assert_eq!(daemon.handshakes(&first.identity()), (1, 0));
+    assert_eq!(daemon.handshakes(&second.identity()), (1, 0));
+
+    drop(stream);
+    assert_eq!(daemon.stop().await, Shutdown::Drained);
+}
+
+#[tokio::test]
+async fn socks4_client_through_three_hops() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let third = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let daemon = Daemon::start(&chains(&[&first, &second, &third])).await;
+
+    let mut stream = socks4_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"ping").await;
+    assert_echo(&mut stream, &[0xab; 4096]).await;
+    assert_eq!(third.connections(), 1);
+
+    drop(stream);
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn slow_hop_still_connects() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Delay(Duration::from_millis(200))).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let daemon = Daemon::start(&chains(&[&first, &second])).await;
+
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"late but fine").await;
+
+    drop(stream);
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn refusal_from_middle_hop_blames_next_hop() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Refuse).await;
+    let third = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let daemon = Daemon::start(&chains(&[&first, &second, &third])).await;
+
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(daemon.handshakes(&first.identity()), (1, 0));
+    assert_eq!(daemon.handshakes(&second.identity()), (0, 0));
+    assert_eq!(daemon.handshakes(&third.identity()), (0, 1));
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn refusal_from_last_hop_blames_nobody() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Refuse).await;
+    let daemon = Daemon::start(&chains(&[&first, &second])).await;
+
+    assert!(socks4_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(daemon.handshakes(&first.identity()), (1, 0));
+    assert_eq!(daemon.handshakes(&second.identity()), (0, 0));
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn wrong_version_blames_replying_hop() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::WrongVersion).await;
+    let daemon = Daemon::start(&chains(&[&first, &second])).await;
+
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(daemon.handshakes(&first.identity()), (1, 0));
+    assert_eq!(daemon.handshakes(&second.identity()), (0, 1));
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn close_mid_handshake_fails_session() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::CloseMidHandshake).await;
+    let second = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let daemon = Daemon::start(&chains(&[&first, &second])).await;
+
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(daemon.handshakes(&first.identity()), (0, 1));
+    assert_eq!(second.connections(), 0);
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn breaker_keeps_failing_proxy_out_until_a_trial_succeeds() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::CloseMidHandshake).await;
+    let breaker = "[breaker]\nfailures = 2\ncooldown = 1\n";
+    let daemon = Daemon::start(&format!("{}{}", breaker, proxy.chain_toml())).await;
+    let metrics = || daemon.context().metrics().encode().unwrap();
+    let state = |value| {
+        format!("rproxychainsd_proxy_breaker_state{{proxy=\"{}\"}} {}", proxy.identity(), value)
+    };
+
+    for _ in 0..3 {
+        assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    }
+
+    // The third session found the proxy's breaker open and did not try it
+    assert_eq!(proxy.connections(), 2);
+    assert!(metrics().contains(&state(2)), "{}", metrics());
+
+    // After the cooldown one trial connection goes through, and opens the breaker again
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(proxy.connections(), 3);
+
+    let config = daemon.context().reloader().subscribe().borrow().clone();
+    let hop = config.chains()[0].entries()[0].hop();
+    daemon.context().proxies().handshake_succeeded(&*hop, Duration::from_millis(5));
+
+    assert!(socks5_connect(daemon.addr(), echo.addr()).await.is_err());
+    assert_eq!(proxy.connections(), 4);
+
+    let metrics = metrics();
+    for (to, count) in [("open", 2), ("half_open", 1), ("closed", 1)] {
+        let transitions = format!(
+            "rproxychainsd_proxy_breaker_transitions_total{{proxy=\"{}\",state=\"{}\"}} {}",
+            proxy.identity(),
+            to,
+            count
+        );
+        assert!(metrics.contains(&transitions), "{}", metrics);
+    }
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn sessions_take_warm_chains() {
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let warm = "warm = { size = 2, max_age = 30 }";
+    let daemon = Daemon::start_with(warm, &chains(&[&first, &second])).await;
+    let pool = || daemon.context().warm_pool().len("default");
+    let warming = tokio::spawn(keep_warm(daemon.context().clone()));
+
+    // Negotiated up to the second hop, which hasn't been asked for anything yet
+    assert!(eventually(|| pool() == 2).await);
+    assert_eq!((first.connections(), second.connections()), (2, 2));
+    assert!(second.targets().is_empty());
+
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"through a warm chain").await;
+    assert_eq!(second.targets(), [echo.addr().to_string()]);
+
+    // The session's tunnel was already there, and is replaced in the background
+    assert!(eventually(|| pool() == 2).await);
+    assert_eq!(first.connections(), 3);
+
+    // Rotating starts over with new chains
+    daemon.context().warm_pool().clear();
+    assert!(eventually(|| pool() == 2).await);
+    assert_eq!(first.connections(), 5);
+
+    warming.abort();
+    drop(stream);
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn warm_chains_leave_trials_to_sessions() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let server = "warm = { size = 1, max_age = 30 }\n[breaker]\nfailures = 1\ncooldown = 1\n";
+    let daemon = Daemon::start_with(server, &proxy.chain_toml()).await;
+    let pool = || daemon.context().warm_pool().len("default");
+
+    let config = daemon.context().reloader().subscribe().borrow().clone();
+    let hop = config.chains()[0].entries()[0].hop();
+    daemon.context().proxies().handshake_failed(&*hop);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    // The cooldown is over, but a tunnel parked in the pool can't be the trial
+    let warming = tokio::spawn(keep_warm(daemon.context().clone()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!((pool(), proxy.connections()), (0, 0));
+
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"the trial").await;
+
+    // Closed again by the session's trial, so warming picks up
+    assert!(eventually(|| pool() == 1).await);
+    assert_eq!(proxy.connections(), 2);
+
+    warming.abort();
+    drop(stream);
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn shutdown_drains_open_sessions() {
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let daemon = Daemon::start(&chains(&[&proxy])).await;
+
+    // Still open when the drain timeout runs out
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"stay").await;
+    assert_eq!(daemon.stop().await, Shutdown::Forced(1));
+}
+
+#[tokio::test]
+async fn metrics_requests_without_a_line_end_are_cut_off() {
+    let port = free_port().await;
+    let endpoint = tokio::spawn(serve(Arc::new(Metrics::new()), "127.0.0.1", port));
+
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    };
+
+    // Exactly as much as is read, so nothing is left unread when the connection closes
+    stream.write_all(&[b'A'; 8192]).await.unwrap();
+
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+    assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+
+    // Nor does a client get to keep the connection by never finishing its request
+    let mut idle = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    idle.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+    let closed = timeout(Duration::from_secs(10), idle.read(&mut [0; 1])).await.unwrap();
+    assert_eq!(closed.unwrap(), 0);
+
+    endpoint.abort();
+}
+
+#[tokio::test]
+async fn admin_requests_are_bounded() {
+    let proxy = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let daemon = Daemon::start(&proxy.chain_toml()).await;
+    let path = socket_path();
+    let context = daemon.context().clone();
+    let admin = {
+        let path = path.clone();
+        tokio::spawn(async move { admin::serve(context, &path, 0o600).await })
+    };
+
+    let mark = |proxy: String| Request::Mark {
+        proxy,
+        state: Mark::Down,
+    };
+    assert!(eventually(|| path.exists()).await);
+    admin::request(&path, &mark(proxy.identity())).await.unwrap();
+
+    let error = admin::request(&path, &mark("socks5://10.9.9.9:1080".to_owned())).await;
+    assert_eq!(error.err().unwrap().to_string(), "socks5://10.9.9.9:1080 is not in the config");
+
+    // Exactly as much as is read, so nothing is left unread when the connection closes
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream.write_all(&vec![b'{'; 64 * 1024]).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+    assert!(response.contains("request longer than 65536 bytes"), "{}", response);
+
+    admin.abort();
+    admin::cleanup(&path).await;
+    daemon.stop().await;
+}
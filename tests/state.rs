@@ -0,0 +1,95 @@
+mod support;
+
+use rproxychainsd::chain::HandshakeObserver;
+use rproxychainsd::context::Context;
+use rproxychainsd::reload::Reloader;
+use rproxychainsd::state::{load, save};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use support::TempDir;
+
+const SLOW: &str = "socks5://10.0.0.1:1080";
+const DEAD: &str = "http://10.0.0.2:3128";
+
+fn context(path: &Path) -> Context {
+    let config = format!(
+        "[server]\nhost = \"127.0.0.1\"\nport = 1080\n\
+         [breaker]\nfailures = 2\ncooldown = 300\n\
+         [state]\npath = {:?}\nmax_age = 600\n\
+         [[chains]]\nentries = [[\"socks5\", \"10.0.0.1\", 1080], [\"http\", \"10.0.0.2\", 3128]]\n",
+        path
+    );
+
+    Context::new(Reloader::new("config.toml", config.parse().unwrap()))
+}
+
+fn stats(context: &Context, identity: &str) -> Value {
+    let snapshot = context.proxies().snapshot();
+    let (_, stats) = snapshot.into_iter().find(|(proxy, _)| proxy == identity).unwrap();
+    serde_json::to_value(stats).unwrap()
+}
+
+#[tokio::test]
+async fn proxy_state_survives_a_restart() {
+    let dir = TempDir::new();
+    let path = dir.path().join("state.json");
+
+    let before = context(&path);
+    let config = before.reloader().subscribe().borrow().clone();
+    let (slow, dead) =
+        (config.chains()[0].entries()[0].hop(), config.chains()[0].entries()[1].hop());
+
+    for millis in [100, 200] {
+        before.proxies().handshake_succeeded(&*slow, Duration::from_millis(millis));
+    }
+
+    for _ in 0..2 {
+        before.proxies().handshake_failed(&*dead);
+    }
+
+    // Nothing to load on the first start
+    load(&before).await.unwrap();
+    save(&before).await.unwrap();
+
+    let after = context(&path);
+    load(&after).await.unwrap();
+
+    assert_eq!(stats(&after, SLOW)["latency_ewma_ms"], 120.0);
+    assert_eq!(stats(&after, SLOW)["last_latency_ms"], 200);
+    assert_eq!(stats(&after, DEAD)["health"], "down");
+    assert_eq!(stats(&after, DEAD)["breaker"], "open");
+
+    // The dead proxy's cooldown carries on, so only the slow one is left to pick from
+    let config = after.reloader().subscribe().borrow().clone();
+    assert!(!after.proxies().is_usable(&config.chains()[0].entries()[1]));
+    for _ in 0..10 {
+        assert_eq!(after.selector().make_chain(&config, "default").unwrap()[0].to_string(), SLOW);
+    }
+
+    let metrics = after.metrics().encode().unwrap();
+    let state = format!("rproxychainsd_proxy_breaker_state{{proxy=\"{}\"}} 2", DEAD);
+    assert!(metrics.contains(&state), "{}", metrics);
+}
+
+#[tokio::test]
+async fn stale_entries_are_ignored() {
+    let dir = TempDir::new();
+    let path = dir.path().join("state.json");
+    let saved = format!(
+        "{{\"proxies\": {{\"{}\": {{\"updated_at\": 1000, \"last_outcome\": \"down\", \
+         \"last_latency_ms\": null, \"latency_ewma_ms\": null, \"consecutive_failures\": 9, \
+         \"breaker\": \"open\", \"breaker_trips\": 3, \"breaker_until\": 99999999999}}}}}}",
+        DEAD
+    );
+    fs::write(&path, saved).unwrap();
+
+    let context = context(&path);
+    load(&context).await.unwrap();
+    assert!(context.proxies().snapshot().is_empty());
+
+    fs::write(&path, "{\"proxies\": [").unwrap();
+    let error = load(&context).await.err().unwrap().to_string();
+    assert!(error.contains("could not read state file"), "{}", error);
+}
@@ -0,0 +1,1000 @@
+// Shared by several test crates, each of which only uses part of it
+#![allow(dead_code)]
+
+use anyhow::{ensure, Result};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, SanType};
+use rproxychainsd::auth::Credentials;
+use rproxychainsd::config::{Config, Proxy};
+use rproxychainsd::context::Context;
+use rproxychainsd::hop::Hop;
+use rproxychainsd::reload::Reloader;
+use rproxychainsd::server::{Server, Shutdown};
+use rproxychainsd::tls::fingerprint;
+use std::io::Error as IoError;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, fs, process};
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Socks4,
+    Socks5,
+    Http,
+}
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// What a mock does once it has read a request
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Behavior {
+    Accept,
+    Refuse,
+    Delay(Duration),
+    WrongVersion,
+    CloseMidHandshake,
+    // Hangs up if anything arrives before it answered, like proxies that reject pipelining
+    RejectEarlyData,
+    // Accepts, then connects to the target from this loopback address
+    Egress(Ipv4Addr),
+}
+
+// Targets as requested, "host:port" for hostnames
+type Targets = Arc<Mutex<Vec<String>>>;
+
+pub struct MockProxy {
+    kind: Kind,
+    tls: bool,
+    addr: SocketAddrV4,
+    connections: Arc<AtomicUsize>,
+    targets: Targets,
+    task: JoinHandle<()>,
+}
+
+// A mock on a Unix socket, like Tor's SocksPort unix:/run/tor/socks
+pub struct UnixMockProxy {
+    path: PathBuf,
+    connections: Arc<AtomicUsize>,
+    targets: Targets,
+    task: JoinHandle<()>,
+}
+
+pub struct EchoServer {
+    addr: SocketAddrV4,
+    task: JoinHandle<()>,
+}
+
+// A "what is my IP" endpoint that answers with the address requests come from, after
+// forwarded_for if given, like endpoints that echo X-Forwarded-For
+pub struct IpEchoServer {
+    addr: SocketAddrV4,
+    task: JoinHandle<()>,
+}
+
+pub struct Daemon {
+    addr: SocketAddrV4,
+    context: Context,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<Shutdown>>,
+}
+
+async fn listen() -> (TcpListener, SocketAddrV4) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    (listener, addr)
+}
+
+// Hands out a port that was free a moment ago, for things that bind on their own
+pub async fn free_port() -> u16 {
+    listen().await.1.port()
+}
+
+static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+// A path in the temp dir for a Unix socket, nothing is created there
+pub fn socket_path() -> PathBuf {
+    let index = NEXT_SOCKET.fetch_add(1, Ordering::SeqCst);
+    env::temp_dir().join(format!("rproxychainsd-test-{}-{}.sock", process::id(), index))
+}
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// A fresh directory in the temp dir, removed with everything in it when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let index = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("rproxychainsd-dir-{}-{}", process::id(), index));
+        fs::create_dir_all(&path).unwrap();
+
+        Self {
+            path,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Retries for a while, for sockets bound by a daemon that is still coming up
+pub async fn unix_connect(path: &Path) -> Result<UnixStream> {
+    for _ in 0..100 {
+        if let Ok(stream) = UnixStream::connect(path).await {
+            return Ok(stream);
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    Ok(UnixStream::connect(path).await?)
+}
+
+async fn read_until_nul<S: Stream>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(buf),
+            byte => buf.push(byte),
+        }
+    }
+}
+
+// Anything read here is lost, which is fine since the mock hangs up if there was something
+async fn has_early_data<S: Stream>(stream: &mut S) -> bool {
+    let mut buf = [0; 1];
+    matches!(timeout(Duration::from_millis(50), stream.read(&mut buf)).await, Ok(Ok(1..)))
+}
+
+// Returns the target of the request, or None if the mock is supposed to drop the connection
+async fn socks4_handshake<S: Stream>(stream: &mut S, behavior: Behavior) -> Result<Option<String>> {
+    let mut request = [0; 8];
+    stream.read_exact(&mut request).await?;
+    read_until_nul(stream).await?;
+
+    let port = u16::from_be_bytes([request[2], request[3]]);
+    let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+
+    // SOCKS4a
+    let target = match request[4..] {
+        [0, 0, 0, 1..=255] => {
+            format!("{}:{}", String::from_utf8(read_until_nul(stream).await?)?, port)
+        }
+        _ => SocketAddrV4::new(ip, port).to_string(),
+    };
+
+    if behavior == Behavior::RejectEarlyData && has_early_data(stream).await {
+        return Ok(None);
+    }
+
+    let (version, result) = match behavior {
+        Behavior::CloseMidHandshake => return Ok(None),
+        Behavior::Refuse => (0, 91),
+        Behavior::WrongVersion => (5, 90),
+        Behavior::Accept | Behavior::Delay(_) | Behavior::RejectEarlyData | Behavior::Egress(_) => {
+            (0, 90)
+        }
+    };
+
+    if let Behavior::Delay(delay) = behavior {
+        sleep(delay).await;
+    }
+
+    stream.write_all(&[version, result, 0, 0, 0, 0, 0, 0]).await?;
+    Ok((result == 90 && version == 0).then_some(target))
+}
+
+async fn socks5_handshake<S: Stream>(stream: &mut S, behavior: Behavior) -> Result<Option<String>> {
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    if behavior == Behavior::CloseMidHandshake {
+        return Ok(None);
+    }
+
+    if behavior == Behavior::RejectEarlyData && has_early_data(stream).await {
+        return Ok(None);
+    }
+
+    if behavior == Behavior::WrongVersion {
+        stream.write_all(&[4, 0]).await?;
+        return Ok(None);
+    }
+
+    stream.write_all(&[5, 0]).await?;
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+
+    let host = match request[3] {
+        3 => {
+            let mut host = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut host).await?;
+            String::from_utf8(host)?
+        }
+        _ => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+    };
+
+    let target = format!("{}:{}", host, stream.read_u16().await?);
+
+    if behavior == Behavior::RejectEarlyData && has_early_data(stream).await {
+        return Ok(None);
+    }
+
+    if let Behavior::Delay(delay) = behavior {
+        sleep(delay).await;
+    }
+
+    let reply = if behavior == Behavior::Refuse { 5 } else { 0 };
+    stream.write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x04, 0xd2]).await?;
+    Ok((reply == 0).then_some(target))
+}
+
+async fn http_handshake<S: Stream>(stream: &mut S, behavior: Behavior) -> Result<Option<String>> {
+    let mut header = vec![];
+
+    while !header.ends_with(b"\r\n\r\n") {
+        header.push(stream.read_u8().await?);
+    }
+
+    let header = String::from_utf8(header)?;
+    let target = header.split(' ').nth(1).unwrap().to_owned();
+
+    if behavior == Behavior::RejectEarlyData && has_early_data(stream).await {
+        return Ok(None);
+    }
+
+    let reply: &[u8] = match behavior {
+        Behavior::CloseMidHandshake => return Ok(None),
+        Behavior::Refuse => b"HTTP/1.1 403 Forbidden\r\n\r\n",
+        Behavior::WrongVersion => b"SOCKS/1.1 200 OK\r\n\r\n",
+        Behavior::Accept | Behavior::Delay(_) | Behavior::RejectEarlyData | Behavior::Egress(_) => {
+            b"HTTP/1.1 200 Connection established\r\nProxy-Agent: mock\r\n\r\n"
+        }
+    };
+
+    if let Behavior::Delay(delay) = behavior {
+        sleep(delay).await;
+    }
+
+    stream.write_all(reply).await?;
+    Ok(reply.starts_with(b"HTTP/1.1 200").then_some(target))
+}
+
+async fn serve_mock<S: Stream>(
+    mut stream: S,
+    kind: Kind,
+    behavior: Behavior,
+    targets: Targets,
+) -> Result<()> {
+    let target = match kind {
+        Kind::Socks4 => socks4_handshake(&mut stream, behavior).await?,
+        Kind::Socks5 => socks5_handshake(&mut stream, behavior).await?,
+        Kind::Http => http_handshake(&mut stream, behavior).await?,
+    };
+
+    // Hostnames are resolved here, like a real proxy would
+    if let Some(target) = target {
+        targets.lock().unwrap().push(target.clone());
+
+        let mut upstream = match behavior {
+            Behavior::Egress(source) => {
+                let socket = TcpSocket::new_v4()?;
+                socket.bind(SocketAddrV4::new(source, 0).into())?;
+                let addr = lookup_host(target).await?.find(SocketAddr::is_ipv4).unwrap();
+                socket.connect(addr).await?
+            }
+            _ => TcpStream::connect(target).await?,
+        };
+
+        copy_bidirectional(&mut stream, &mut upstream).await?;
+    }
+
+    Ok(())
+}
+
+impl MockProxy {
+    pub async fn spawn(kind: Kind, behavior: Behavior) -> Self {
+        let (listener, addr) = listen().await;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let targets = Targets::default();
+        let requested = targets.clone();
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve_mock(stream, kind, behavior, requested.clone()));
+            }
+        });
+
+        Self {
+            kind,
+            tls: false,
+            addr,
+            connections,
+            targets,
+            task,
+        }
+    }
+
+    // Only speaks the proxy protocol inside TLS, like a proxy behind stunnel
+    pub async fn spawn_tls(kind: Kind, behavior: Behavior, config: Arc<ServerConfig>) -> Self {
+        let (listener, addr) = listen().await;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let targets = Targets::default();
+        let requested = targets.clone();
+        let acceptor = TlsAcceptor::from(config);
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                let requested = requested.clone();
+
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let _ = serve_mock(stream, kind, behavior, requested).await;
+                    }
+                });
+            }
+        });
+
+        Self {
+            kind,
+            tls: true,
+            addr,
+            connections,
+            targets,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+
+    // Identity as used in logs, metrics and stats, e.g. socks5://127.0.0.1:1080
+    pub fn identity(&self) -> String {
+        let tls = if self.tls { "+tls" } else { "" };
+        format!("{}{}://{}", self.scheme(), tls, self.addr)
+    }
+
+    // Plain hops only, TLS ones need their options from a TlsClient
+    pub fn hop(&self) -> Arc<dyn Hop> {
+        assert!(!self.tls);
+        self.identity().parse::<Proxy>().unwrap().hop()
+    }
+
+    // A [[chains]] group with just this proxy in it
+    pub fn chain_toml(&self) -> String {
+        format!(
+            "[[chains]]\nentries = [[\"{}\", \"{}\", {}]]\n",
+            self.scheme(),
+            self.addr.ip(),
+            self.addr.port()
+        )
+    }
+
+    fn scheme(&self) -> &'static str {
+        match self.kind {
+            Kind::Socks4 => "socks4",
+            Kind::Socks5 => "socks5",
+            Kind::Http => "http",
+        }
+    }
+}
+
+impl Drop for MockProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl UnixMockProxy {
+    pub async fn spawn(kind: Kind, behavior: Behavior) -> Self {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let targets = Targets::default();
+        let requested = targets.clone();
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve_mock(stream, kind, behavior, requested.clone()));
+            }
+        });
+
+        Self {
+            path,
+            connections,
+            targets,
+            task,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for UnixMockProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl EchoServer {
+    pub async fn spawn() -> Self {
+        let (listener, addr) = listen().await;
+
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+
+        Self {
+            addr,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl IpEchoServer {
+    pub async fn spawn(forwarded_for: Option<Ipv4Addr>) -> Self {
+        let (listener, addr) = listen().await;
+
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, peer)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = vec![];
+
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(stream.read_u8().await?);
+                    }
+
+                    let origin = match forwarded_for {
+                        Some(forwarded_for) => format!("{}, {}", forwarded_for, peer.ip()),
+                        None => peer.ip().to_string(),
+                    };
+
+                    let body = format!("{{\"origin\": \"{}\"}}", origin);
+                    let response = format!(
+                        "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await?;
+                    Ok::<_, IoError>(())
+                });
+            }
+        });
+
+        Self {
+            addr,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/ip", self.addr)
+    }
+}
+
+impl Drop for IpEchoServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Daemon {
+    // Runs the real server in-process on a fresh port, chains_toml being the [[chains]] part
+    pub async fn start(chains_toml: &str) -> Self {
+        Self::start_with("", chains_toml).await
+    }
+
+    // Same, with extra settings for the [server] section
+    pub async fn start_with(server_toml: &str, chains_toml: &str) -> Self {
+        let port = free_port().await;
+
+        let config: Config = format!(
+            "[server]\nhost = \"127.0.0.1\"\nport = {}\ndrain_timeout = 1\n{}\n{}",
+            port, server_toml, chains_toml
+        )
+        .parse()
+        .unwrap();
+
+        let context = Context::new(Reloader::new("test.toml", config));
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let server = Server::new(context.clone());
+
+        let task = tokio::spawn(async move {
+            server
+                .run(async {
+                    let _ = shutdown_receiver.await;
+                })
+                .await
+        });
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+
+        // Wait for the listener to come up
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        Self {
+            addr,
+            context,
+            shutdown: Some(shutdown),
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    // Failures and successes recorded for the given proxy identity
+    pub fn handshakes(&self, identity: &str) -> (u64, u64) {
+        let snapshot = self.context.proxies().snapshot();
+
+        let stats = snapshot
+            .into_iter()
+            .find(|(proxy, _)| proxy == identity)
+            .map(|(_, stats)| serde_json::to_value(stats).unwrap());
+
+        match stats {
+            Some(stats) => {
+                (stats["successes"].as_u64().unwrap(), stats["failures"].as_u64().unwrap())
+            }
+            None => (0, 0),
+        }
+    }
+
+    pub async fn stop(mut self) -> Shutdown {
+        self.shutdown.take().unwrap().send(()).unwrap();
+        (&mut self.task).await.unwrap().unwrap()
+    }
+}
+
+// Polls for up to two seconds, for things background tasks get to on their own time
+pub async fn eventually(mut check: impl FnMut() -> bool) -> bool {
+    for _ in 0..200 {
+        if check() {
+            return true;
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    false
+}
+
+pub async fn socks5_connect(proxy: SocketAddrV4, target: SocketAddrV4) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    socks5_request(&mut stream, target).await?;
+    Ok(stream)
+}
+
+// Same over an already open stream, e.g. one with TLS on it
+pub async fn socks5_request<S: Stream>(stream: &mut S, target: SocketAddrV4) -> Result<()> {
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply == [5, 0], "auth rejected: {:?}", reply);
+    socks5_command(stream, target).await
+}
+
+// Offers only username/password authentication
+pub async fn socks5_login(
+    proxy: SocketAddrV4,
+    target: SocketAddrV4,
+    username: &str,
+    password: &str,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(&[5, 1, 2]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply == [5, 2], "password auth not chosen: {:?}", reply);
+
+    let mut request = vec![1, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply == [1, 0], "password rejected: {:?}", reply);
+
+    socks5_command(&mut stream, target).await?;
+    Ok(stream)
+}
+
+async fn socks5_command<S: Stream>(stream: &mut S, target: SocketAddrV4) -> Result<()> {
+    let mut request = vec![5, 1, 0, 1];
+    request.extend_from_slice(&target.ip().octets());
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[..2] == [5, 0], "request failed: {:?}", reply);
+    Ok(())
+}
+
+pub async fn http_connect(
+    proxy: SocketAddrV4,
+    target: SocketAddrV4,
+    login: Option<(&str, &str)>,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+
+    if let Some((username, password)) = login {
+        let credentials = Credentials::new(username.to_owned(), password.to_owned());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials.to_basic()));
+    }
+
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut header = vec![];
+
+    while !header.ends_with(b"\r\n\r\n") {
+        header.push(stream.read_u8().await?);
+    }
+
+    ensure!(header.starts_with(b"HTTP/1.1 200"), "request failed: {}", String::from_utf8(header)?);
+    Ok(stream)
+}
+
+pub async fn socks4_connect(proxy: SocketAddrV4, target: SocketAddrV4) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&target.ip().octets());
+    request.extend_from_slice(b"test\0");
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[..2] == [0, 90], "request failed: {:?}", reply);
+    Ok(stream)
+}
+
+// Sends a message through the stream and checks it comes back unchanged
+pub async fn assert_echo<S: Stream>(stream: &mut S, message: &[u8]) {
+    stream.write_all(message).await.unwrap();
+    let mut buf = vec![0; message.len()];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(buf, message);
+}
+
+static NEXT_PKI: AtomicUsize = AtomicUsize::new(0);
+
+// A throwaway CA with a server certificate for proxy.test and 127.0.0.1 and a client
+// certificate, written out to files the way the config expects them
+pub struct Pki {
+    dir: PathBuf,
+    ca: CertificateDer<'static>,
+    server_pin: String,
+    client_pin: String,
+    server_config: Arc<ServerConfig>,
+    mutual_server_config: Arc<ServerConfig>,
+}
+
+impl Pki {
+    pub fn generate() -> Self {
+        let index = NEXT_PKI.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("rproxychainsd-test-{}-{}", process::id(), index));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "test ca");
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let mut server_params = CertificateParams::new(vec!["proxy.test".to_owned()]).unwrap();
+        server_params.subject_alt_names.push(SanType::IpAddress(Ipv4Addr::LOCALHOST.into()));
+        let server_key = KeyPair::generate().unwrap();
+        let server = server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        let mut client_params = CertificateParams::new(vec![]).unwrap();
+        client_params.distinguished_name.push(DnType::CommonName, "test client");
+        let client_key = KeyPair::generate().unwrap();
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        fs::write(dir.join("client.pem"), client.pem()).unwrap();
+        fs::write(dir.join("client.key"), client_key.serialize_pem()).unwrap();
+        fs::write(dir.join("server.pem"), server.pem()).unwrap();
+        fs::write(dir.join("server.key"), server_key.serialize_pem()).unwrap();
+
+        let server_pin = pin(server.der());
+        let client_pin = pin(client.der());
+        let provider = Arc::new(default_provider());
+        let chain = vec![server.der().clone()];
+        let key = PrivateKeyDer::try_from(server_key.serialize_der()).unwrap();
+
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain.clone(), key.clone_key())
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+
+        let client_verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+
+        let mutual_server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(chain, key)
+            .unwrap();
+
+        Self {
+            dir,
+            ca: ca.der().clone(),
+            server_pin,
+            client_pin,
+            server_config: Arc::new(server_config),
+            mutual_server_config: Arc::new(mutual_server_config),
+        }
+    }
+
+    pub fn ca(&self) -> PathBuf {
+        self.dir.join("ca.pem")
+    }
+
+    pub fn client_cert(&self) -> PathBuf {
+        self.dir.join("client.pem")
+    }
+
+    pub fn client_key(&self) -> PathBuf {
+        self.dir.join("client.key")
+    }
+
+    pub fn server_cert(&self) -> PathBuf {
+        self.dir.join("server.pem")
+    }
+
+    pub fn server_key(&self) -> PathBuf {
+        self.dir.join("server.key")
+    }
+
+    pub fn server_pin(&self) -> &str {
+        &self.server_pin
+    }
+
+    pub fn client_pin(&self) -> &str {
+        &self.client_pin
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.server_config.clone()
+    }
+
+    // Same server certificate, but clients have to present one signed by the CA
+    pub fn mutual_server_config(&self) -> Arc<ServerConfig> {
+        self.mutual_server_config.clone()
+    }
+
+    // Connects to a TLS listener using the server certificate, optionally with the client one
+    pub async fn connect(
+        &self,
+        addr: SocketAddrV4,
+        client_cert: bool,
+    ) -> Result<TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.clone())?;
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+
+        let config = if client_cert {
+            let cert = fs::read(self.client_cert())?;
+            let key = fs::read(self.client_key())?;
+            let cert = rustls_pemfile::certs(&mut &cert[..]).collect::<Result<Vec<_>, _>>()?;
+            let key = rustls_pemfile::private_key(&mut &key[..])?.unwrap();
+            builder.with_client_auth_cert(cert, key)?
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let stream = TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("proxy.test")?;
+        Ok(TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?)
+    }
+}
+
+fn pin(certificate: &[u8]) -> String {
+    let digest = fingerprint(certificate);
+    let digits: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256:{}", digits)
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+enum MmdbRecord {
+    Empty,
+    Node(usize),
+    Data(usize),
+}
+
+// Writes an IPv4 MaxMind database with 24 bit records that maps each network to a country and
+// an ASN, enough for the GeoIP2 Country and ASN lookups
+pub fn write_mmdb(path: &Path, networks: &[(Ipv4Addr, u8, &str, u32)]) {
+    let mut data = Vec::new();
+    let mut nodes = vec![[MmdbRecord::Empty, MmdbRecord::Empty]];
+
+    for (network, prefix, country, asn) in networks {
+        let offset = data.len();
+        mmdb_map(&mut data, 2);
+        mmdb_string(&mut data, "country");
+        mmdb_map(&mut data, 1);
+        mmdb_string(&mut data, "iso_code");
+        mmdb_string(&mut data, country);
+        mmdb_string(&mut data, "autonomous_system_number");
+        mmdb_u32(&mut data, *asn);
+
+        let bits = u32::from(*network);
+        let mut node = 0;
+
+        for depth in 0..*prefix {
+            let bit = (bits >> (31 - depth) & 1) as usize;
+
+            if depth == prefix - 1 {
+                nodes[node][bit] = MmdbRecord::Data(offset);
+            } else if let MmdbRecord::Node(next) = nodes[node][bit] {
+                node = next;
+            } else {
+                nodes.push([MmdbRecord::Empty, MmdbRecord::Empty]);
+                nodes[node][bit] = MmdbRecord::Node(nodes.len() - 1);
+                node = nodes.len() - 1;
+            }
+        }
+    }
+
+    let node_count = nodes.len();
+    let mut file = Vec::new();
+
+    for record in nodes.iter().flatten() {
+        let value = match record {
+            MmdbRecord::Empty => node_count,
+            MmdbRecord::Node(next) => *next,
+            MmdbRecord::Data(offset) => node_count + 16 + offset,
+        };
+
+        file.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+    }
+
+    file.extend_from_slice(&[0; 16]);
+    file.extend_from_slice(&data);
+    file.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+
+    mmdb_map(&mut file, 9);
+    mmdb_string(&mut file, "binary_format_major_version");
+    mmdb_u16(&mut file, 2);
+    mmdb_string(&mut file, "binary_format_minor_version");
+    mmdb_u16(&mut file, 0);
+    mmdb_string(&mut file, "build_epoch");
+    file.extend_from_slice(&[8, 2]);
+    file.extend_from_slice(&0u64.to_be_bytes());
+    mmdb_string(&mut file, "database_type");
+    mmdb_string(&mut file, "Test");
+    mmdb_string(&mut file, "description");
+    mmdb_map(&mut file, 0);
+    mmdb_string(&mut file, "ip_version");
+    mmdb_u16(&mut file, 4);
+    mmdb_string(&mut file, "languages");
+    file.extend_from_slice(&[0, 4]);
+    mmdb_string(&mut file, "node_count");
+    mmdb_u32(&mut file, node_count as u32);
+    mmdb_string(&mut file, "record_size");
+    mmdb_u16(&mut file, 24);
+
+    fs::write(path, file).unwrap();
+}
+
+fn mmdb_map(out: &mut Vec<u8>, len: usize) {
+    out.push(0xe0 | len as u8);
+}
+
+fn mmdb_string(out: &mut Vec<u8>, value: &str) {
+    out.push(0x40 | value.len() as u8);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn mmdb_u16(out: &mut Vec<u8>, value: u16) {
+    out.push(0xa2);
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn mmdb_u32(out: &mut Vec<u8>, value: u32) {
+    out.push(0xc4);
+    out.extend_from_slice(&value.to_be_bytes());
+}
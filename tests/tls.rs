@@ -0,0 +1,159 @@
+mod support;
+
+use rproxychainsd::chain::Error as ChainError;
+use rproxychainsd::hop::Hop;
+use rproxychainsd::http::HttpHop;
+use rproxychainsd::socks5::Socks5Hop;
+use rproxychainsd::tls::{parse_pin, TlsClient};
+use rproxychainsd::{ChainConnector, HandshakeMode};
+use std::path::Path;
+use std::sync::Arc;
+use support::{assert_echo, socks5_connect, Behavior, Daemon, EchoServer, Kind, MockProxy, Pki};
+
+const MODES: [HandshakeMode; 2] = [HandshakeMode::Pipelined, HandshakeMode::Stepwise];
+
+fn tls_hop(
+    proxy: &MockProxy,
+    sni: Option<&str>,
+    ca: Option<&Path>,
+    client_cert: Option<(&Path, &Path)>,
+    pin: Option<&str>,
+) -> Arc<dyn Hop> {
+    let pin = pin.map(|pin| parse_pin(pin).unwrap());
+    let client = TlsClient::new(sni, ca, client_cert, pin).unwrap();
+
+    let inner: Arc<dyn Hop> = match proxy.identity().split_once('+').unwrap().0 {
+        "socks5" => Arc::new(Socks5Hop::new(proxy.addr())),
+        "http" => Arc::new(HttpHop::new(proxy.addr())),
+        other => panic!("no TLS mock for {}", other),
+    };
+
+    Arc::new(client.wrap(inner))
+}
+
+fn connector(hops: Vec<Arc<dyn Hop>>, mode: HandshakeMode) -> ChainConnector {
+    ChainConnector::new(hops).unwrap().with_mode(mode)
+}
+
+#[tokio::test]
+async fn tls_first_hop_with_ca_and_sni() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn_tls(Kind::Socks5, Behavior::Accept, pki.server_config()).await;
+    let hop = tls_hop(&proxy, Some("proxy.test"), Some(&pki.ca()), None, None);
+    assert_eq!(hop.to_string(), proxy.identity());
+
+    for mode in MODES {
+        let (mut stream, _) =
+            connector(vec![hop.clone()], mode).connect(echo.addr()).await.unwrap();
+        assert_echo(&mut stream, b"over tls").await;
+    }
+}
+
+#[tokio::test]
+async fn tls_is_started_inside_the_tunnel() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks5, Behavior::Accept).await;
+    let second = MockProxy::spawn_tls(Kind::Http, Behavior::Accept, pki.server_config()).await;
+    let third = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+
+    // No SNI, so the certificate is checked against the hop's address
+    let hops = vec![first.hop(), tls_hop(&second, None, Some(&pki.ca()), None, None), third.hop()];
+
+    for mode in MODES {
+        let (mut stream, _) = connector(hops.clone(), mode).connect(echo.addr()).await.unwrap();
+        assert_echo(&mut stream, b"tls in the middle").await;
+    }
+
+    assert_eq!(second.connections(), 2);
+}
+
+#[tokio::test]
+async fn pin_replaces_ca() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn_tls(Kind::Socks5, Behavior::Accept, pki.server_config()).await;
+
+    let hop = tls_hop(&proxy, Some("proxy.test"), None, None, Some(pki.server_pin()));
+    let (mut stream, _) =
+        connector(vec![hop], HandshakeMode::Pipelined).connect(echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"pinned").await;
+
+    let wrong = format!("sha256:{}", "00".repeat(32));
+    let hop = tls_hop(&proxy, Some("proxy.test"), None, None, Some(&wrong));
+    let error =
+        connector(vec![hop], HandshakeMode::Pipelined).connect(echo.addr()).await.err().unwrap();
+
+    match error.downcast_ref() {
+        Some(ChainError::HopFailed {
+            index,
+            hop,
+        }) => {
+            assert_eq!(*index, 0);
+            assert_eq!(*hop, proxy.identity());
+        }
+        other => panic!("unexpected error {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn pin_and_ca_both_have_to_match() {
+    let pki = Pki::generate();
+    let other = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn_tls(Kind::Socks5, Behavior::Accept, pki.server_config()).await;
+
+    let hop = tls_hop(&proxy, Some("proxy.test"), Some(&other.ca()), None, Some(pki.server_pin()));
+    assert!(connector(vec![hop], HandshakeMode::Stepwise).connect(echo.addr()).await.is_err());
+}
+
+#[tokio::test]
+async fn wrong_sni_is_rejected() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy = MockProxy::spawn_tls(Kind::Socks5, Behavior::Accept, pki.server_config()).await;
+
+    let hop = tls_hop(&proxy, Some("elsewhere.test"), Some(&pki.ca()), None, None);
+    assert!(connector(vec![hop], HandshakeMode::Pipelined).connect(echo.addr()).await.is_err());
+}
+
+#[tokio::test]
+async fn client_certificate_is_presented() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let proxy =
+        MockProxy::spawn_tls(Kind::Socks5, Behavior::Accept, pki.mutual_server_config()).await;
+    let (cert, key) = (pki.client_cert(), pki.client_key());
+
+    let hop = tls_hop(&proxy, Some("proxy.test"), Some(&pki.ca()), None, None);
+    assert!(connector(vec![hop], HandshakeMode::Stepwise).connect(echo.addr()).await.is_err());
+
+    let hop = tls_hop(&proxy, Some("proxy.test"), Some(&pki.ca()), Some((&cert, &key)), None);
+    let (mut stream, _) =
+        connector(vec![hop], HandshakeMode::Stepwise).connect(echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"mutual").await;
+}
+
+#[tokio::test]
+async fn daemon_with_tls_hop_from_config() {
+    let pki = Pki::generate();
+    let echo = EchoServer::spawn().await;
+    let first = MockProxy::spawn(Kind::Socks4, Behavior::Accept).await;
+    let second = MockProxy::spawn_tls(Kind::Http, Behavior::Accept, pki.server_config()).await;
+
+    let chains = format!(
+        "{}[[chains]]\nentries = [{{ type = \"http\", host = \"127.0.0.1\", port = {}, tls = {{ sni = \"proxy.test\", ca = {:?} }} }}]\n",
+        first.chain_toml(),
+        second.addr().port(),
+        pki.ca(),
+    );
+
+    let daemon = Daemon::start(&chains).await;
+    let mut stream = socks5_connect(daemon.addr(), echo.addr()).await.unwrap();
+    assert_echo(&mut stream, b"configured tls").await;
+    assert_eq!(daemon.handshakes(&second.identity()), (1, 0));
+
+    drop(stream);
+    daemon.stop().await;
+}